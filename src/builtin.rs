@@ -1,23 +1,45 @@
 use futures::future::BoxFuture;
+use log::{info, warn};
+use regex::Regex;
+use reqwest::header::{ACCEPT_RANGES, RANGE};
+use reqwest::StatusCode;
 use similar::{ChangeTag, TextDiff};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-use std::sync::Arc;
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use std::{error::Error, fs::read_to_string};
 
+#[derive(Default)]
 pub struct BuiltinRegistry {
     commands: HashMap<String, BuiltinFn>,
+    /// Names of the extra values each builtin returns
+    extras: HashMap<String, &'static [&'static str]>,
 }
 
-type BuiltinFn =
-    Arc<dyn Fn(Vec<String>) -> BoxFuture<'static, Result<(), Box<dyn Error>>> + Send + Sync>;
+/// What a builtin returns for `score_variable`
+#[derive(Debug, Default)]
+pub struct BuiltinOutput {
+    /// Stored into `score_variable`, e.g. a pass count
+    pub value: Option<i64>,
+    /// Stored into `{score_variable}_{name}`, e.g. the failure count
+    pub extra: Vec<(&'static str, i64)>,
+}
+
+type BuiltinFn = Arc<
+    dyn Fn(Vec<String>) -> BoxFuture<'static, Result<BuiltinOutput, Box<dyn Error>>> + Send + Sync,
+>;
 
 impl BuiltinRegistry {
     pub fn new() -> Self {
         Self {
             commands: HashMap::new(),
+            extras: HashMap::new(),
         }
     }
 
@@ -27,12 +49,68 @@ impl BuiltinRegistry {
             + Send
             + Sync
             + 'static,
+    {
+        self.commands.insert(
+            name.to_string(),
+            Arc::new(move |args| {
+                let future = function(args);
+                Box::pin(async move { future.await.map(|()| BuiltinOutput::default()) })
+            }),
+        );
+        self
+    }
+
+    /// Register a builtin whose result can be stored with `score_variable`
+    pub fn register_scored<F>(&mut self, name: &str, function: F) -> &mut Self
+    where
+        F: Fn(Vec<String>) -> BoxFuture<'static, Result<i64, Box<dyn Error>>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.commands.insert(
+            name.to_string(),
+            Arc::new(move |args| {
+                let future = function(args);
+                Box::pin(async move {
+                    future.await.map(|value| BuiltinOutput {
+                        value: Some(value),
+                        extra: vec![],
+                    })
+                })
+            }),
+        );
+        self
+    }
+
+    /// Register a builtin that also returns the values named in `extras`
+    pub fn register_detailed<F>(
+        &mut self,
+        name: &str,
+        extras: &'static [&'static str],
+        function: F,
+    ) -> &mut Self
+    where
+        F: Fn(Vec<String>) -> BoxFuture<'static, Result<BuiltinOutput, Box<dyn Error>>>
+            + Send
+            + Sync
+            + 'static,
     {
         self.commands.insert(name.to_string(), Arc::new(function));
+        self.extras.insert(name.to_string(), extras);
         self
     }
 
-    pub async fn execute(&self, name: &str, args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    /// Names of the extra values the builtin `name` returns
+    pub fn extras(&self, name: &str) -> &'static [&'static str] {
+        self.extras.get(name).copied().unwrap_or_default()
+    }
+
+    pub async fn execute(
+        &self,
+        name: &str,
+        args: Vec<String>,
+    ) -> Result<BuiltinOutput, Box<dyn Error>> {
         if let Some(command) = self.commands.get(name) {
             command(args).await
         } else {
@@ -47,37 +125,549 @@ pub fn create_builtin_registry() -> BuiltinRegistry {
         .register("download_and_extract_7z", |args| {
             Box::pin(download_and_extract_7z_builtin(args))
         })
-        .register("diff_file", |args| Box::pin(diff_file_builtin(args)));
+        .register("diff_file", |args| Box::pin(diff_file_builtin(args)))
+        .register("check_format", |args| Box::pin(check_format_builtin(args)))
+        .register("run_clang_tidy", |args| {
+            Box::pin(run_clang_tidy_builtin(args))
+        })
+        .register("check_submission_size", |args| {
+            Box::pin(check_submission_size_builtin(args))
+        })
+        .register("run_limited", |args| Box::pin(run_limited_builtin(args)))
+        .register("normalize_eol", |args| {
+            Box::pin(normalize_eol_builtin(args))
+        })
+        .register("diff_remote", |args| Box::pin(diff_remote_builtin(args)))
+        .register("run_expect", |args| Box::pin(run_expect_builtin(args)))
+        .register("diff_files", |args| Box::pin(diff_files_builtin(args)))
+        .register("check_cpu_time", |args| {
+            Box::pin(check_cpu_time_builtin(args))
+        })
+        .register("check_archive_structure", |args| {
+            Box::pin(check_archive_structure_builtin(args))
+        })
+        .register("diff_numeric", |args| Box::pin(diff_numeric_builtin(args)))
+        .register("run_script", |args| Box::pin(run_script_builtin(args)))
+        .register_scored("run_testcases", |args| {
+            Box::pin(run_testcases_builtin(args))
+        })
+        .register_detailed("run_gtest", GTEST_EXTRAS, |args| {
+            Box::pin(run_gtest_builtin(args))
+        })
+        .register("check_banned_tokens", |args| {
+            Box::pin(check_banned_tokens_builtin(args))
+        })
+        .register("download_and_extract_all", |args| {
+            Box::pin(download_and_extract_all_builtin(args))
+        })
+        .register("check_binary", |args| Box::pin(check_binary_builtin(args)))
+        .register("compile_with_flags", |args| {
+            Box::pin(compile_with_flags_builtin(args))
+        })
+        .register("validate_json_schema", |args| {
+            Box::pin(validate_json_schema_builtin(args))
+        })
+        .register("check_header", |args| Box::pin(check_header_builtin(args)))
+        .register("check_executable", |args| {
+            Box::pin(check_executable_builtin(args))
+        })
+        .register("make_executable", |args| {
+            Box::pin(make_executable_builtin(args))
+        })
+        .register("run_deterministic", |args| {
+            Box::pin(run_deterministic_builtin(args))
+        })
+        .register("diff_tree", |args| Box::pin(diff_tree_builtin(args)));
 
     registry
 }
 
+/// Programs the builtin `action` runs given its `args`, which
+/// `allowed_commands` must list like the executable of a custom command
+pub fn spawned_programs(action: &str, args: &[String]) -> Vec<String> {
+    let arg = |index: usize| args.get(index).cloned().into_iter().collect();
+    match action {
+        "check_format" => vec!["clang-format".to_string()],
+        "run_clang_tidy" => vec!["clang-tidy".to_string()],
+        "run_script" => vec!["sh".to_string()],
+        "run_expect" | "run_deterministic" | "run_testcases" | "run_gtest" => arg(0),
+        "check_cpu_time" => arg(1),
+        "run_limited" => arg(2),
+        "compile_with_flags" => match args.first() {
+            Some(dir) if Path::new(dir).join("CMakeLists.txt").exists() => {
+                vec!["cmake".to_string()]
+            }
+            Some(_) => vec!["make".to_string()],
+            None => vec![],
+        },
+        _ => vec![],
+    }
+}
+
 async fn download_and_extract_7z_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
     let url = args.first().ok_or("URL not set in arguments")?;
     let output = args.get(1).ok_or("output dir not set in arguments")?;
     download_and_extract_7z(url, output).await
 }
 
+/// Unique path for a downloaded file, kept outside of any extract dir
+fn download_temp_path(extension: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!(
+        "canvasbot-{}-{}.{}",
+        std::process::id(),
+        id,
+        extension
+    ))
+}
+
 async fn download_and_extract_7z(url: &str, output_dir: &str) -> Result<(), Box<dyn Error>> {
+    download_and_extract(url, output_dir, Some(ArchiveFormat::SevenZ)).await
+}
+
+async fn download_and_extract_all_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    if args.is_empty() {
+        return Err("No url:output_dir pairs set".into());
+    }
+    for pair in &args {
+        // split at the last colon, URLs have one of their own
+        let (url, output) = pair
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Expected url:output_dir, got {}", pair))?;
+        download_and_extract(url, output, None).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ArchiveFormat {
+    SevenZ,
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Detect the format from the archive's magic bytes, since Canvas
+    /// download URLs carry no file extension
+    fn detect(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut magic = [0; 6];
+        let read = std::io::Read::read(&mut File::open(path)?, &mut magic)?;
+        match &magic[..read] {
+            [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C] => Ok(Self::SevenZ),
+            [b'P', b'K', 3, 4, ..] => Ok(Self::Zip),
+            [0x1F, 0x8B, ..] => Ok(Self::TarGz),
+            _ => Err("Unsupported archive format, expected 7z, zip or tar.gz".into()),
+        }
+    }
+
+    /// Extract `archive` into `dir`, charging what is written to `charge`
+    fn extract(
+        self,
+        archive: &Path,
+        dir: &Path,
+        charge: &mut QuotaCharge,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self {
+            Self::SevenZ => sevenz_rust::decompress_file_with_extract_fn(
+                archive,
+                dir,
+                |entry, reader, dest| {
+                    let mut reader = QuotaReader {
+                        inner: reader,
+                        charge: &mut *charge,
+                    };
+                    sevenz_rust::default_entry_extract_fn(entry, &mut reader, dest)
+                },
+            )?,
+            Self::Zip => extract_zip(File::open(archive)?, dir, charge)?,
+            Self::TarGz => {
+                let reader = QuotaReader {
+                    inner: flate2::read::GzDecoder::new(File::open(archive)?),
+                    charge,
+                };
+                tar::Archive::new(reader).unpack(dir)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extract `archive` into `dir` like `ZipArchive::extract`, one entry at a
+/// time so that each is charged to the disk quota as it is written
+fn extract_zip(
+    archive: File,
+    dir: &Path,
+    charge: &mut QuotaCharge,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut archive = zip::ZipArchive::new(archive)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = entry
+            .enclosed_name()
+            .ok_or_else(|| format!("Unsafe path {} in zip archive", entry.name()))?;
+        let path = dir.join(name);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let (is_symlink, mode) = (entry.is_symlink(), entry.unix_mode());
+        let mut reader = QuotaReader {
+            inner: &mut entry,
+            charge: &mut *charge,
+        };
+        if is_symlink {
+            let mut target = Vec::new();
+            std::io::Read::read_to_end(&mut reader, &mut target)?;
+            std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(&target), &path)?;
+            continue;
+        }
+        std::io::copy(&mut reader, &mut File::create(&path)?)?;
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+/// Download the archive at `url` and extract it into a fresh `output_dir`,
+/// detecting its format unless given. The extracted files stay charged to
+/// the disk quota until `output_dir` is removed.
+async fn download_and_extract(
+    url: &str,
+    output_dir: &str,
+    format: Option<ArchiveFormat>,
+) -> Result<(), Box<dyn Error>> {
     // Local path to save the downloaded file
-    let downloaded_path = Path::new(output_dir).join("submitted.7z");
+    let downloaded_path = download_temp_path("archive");
 
     // Directory to extract the contents
     let extract_dir = Path::new(output_dir);
     if extract_dir.exists() {
+        release_disk_quota(extract_dir);
         std::fs::remove_dir_all(extract_dir)?;
     }
     std::fs::create_dir_all(extract_dir)?;
 
     // Download the file
-    let response = reqwest::get(url).await?;
-    let content = response.bytes().await?;
-    let mut dest = File::create(&downloaded_path)?;
-    dest.write_all(&content)?;
+    let mut charge = QuotaCharge::start().await?;
+    if let Err(e) = download_resumable(url, &downloaded_path, &mut charge).await {
+        let _ = std::fs::remove_file(&downloaded_path);
+        return Err(e);
+    }
+
+    // Extract the archive, then drop it so only its contents remain
+    let (archive, dir) = (downloaded_path.clone(), extract_dir.to_path_buf());
+    let extracted = tokio::task::spawn_blocking(move || {
+        let format = match format {
+            Some(format) => format,
+            None => ArchiveFormat::detect(&archive)?,
+        };
+        if let Err(e) = format.extract(&archive, &dir, &mut charge) {
+            // the archive crates bury a quota error in their own
+            return Err(charge.exceeded().map_or(e, Into::into));
+        }
+        Ok(charge)
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.into()));
+    let archive_bytes = std::fs::metadata(&downloaded_path)?.len();
+    std::fs::remove_file(&downloaded_path)?;
+    let mut charge = match extracted {
+        Ok(charge) => charge,
+        Err(e) => {
+            // a partial extraction is of no use to the pipeline
+            let _ = std::fs::remove_dir_all(extract_dir);
+            return Err(e);
+        }
+    };
+    charge.release(archive_bytes);
+    charge.hold(extract_dir);
+    Ok(())
+}
+
+/// Disk usage of downloads and extractions, bounded by `max_workspace_bytes`
+/// so concurrent grading cannot fill the disk
+struct DiskQuota {
+    limit: u64,
+    state: std::sync::Mutex<QuotaState>,
+    released: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    /// Bytes charged, in flight or held
+    used: u64,
+    /// Downloads and extractions running, whose bytes may still be released
+    in_flight: usize,
+    /// Bytes extracted into each directory, held until it is removed
+    held: HashMap<PathBuf, u64>,
+}
+
+impl DiskQuota {
+    fn new(limit: u64) -> DiskQuota {
+        DiskQuota {
+            limit,
+            state: std::sync::Mutex::new(QuotaState::default()),
+            released: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Return the bytes held for `dir` or the directories below it
+    fn release_dir(&self, dir: &Path) {
+        let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let mut state = self.state.lock().unwrap();
+        let mut released = 0;
+        state.held.retain(|path, bytes| {
+            let inside = path.starts_with(&dir);
+            if inside {
+                released += *bytes;
+            }
+            !inside
+        });
+        state.used -= released;
+        self.released.notify_waiters();
+    }
+}
+
+static DISK_QUOTA: OnceLock<DiskQuota> = OnceLock::new();
+
+/// Bound the bytes that downloads and extracted files may use at once
+pub fn set_disk_quota(limit: u64) {
+    if DISK_QUOTA.set(DiskQuota::new(limit)).is_err() {
+        warn!("Disk quota is already set");
+    }
+}
+
+/// Return the bytes held for the files extracted into `dir` or below it,
+/// which is about to be removed
+pub fn release_disk_quota(dir: &Path) {
+    if let Some(quota) = DISK_QUOTA.get() {
+        quota.release_dir(dir);
+    }
+}
+
+/// Writing more would exceed the disk quota
+#[derive(Debug)]
+pub struct QuotaExceeded(u64);
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Downloads and extracted files exceed the workspace quota of {} bytes",
+            self.0
+        )
+    }
+}
 
-    // Extract the 7z file
-    sevenz_rust::decompress_file(&downloaded_path, extract_dir)?;
+impl Error for QuotaExceeded {}
+
+/// Bytes charged to the disk quota by a running download or extraction,
+/// returned to it on drop unless held for an extracted directory
+pub struct QuotaCharge {
+    bytes: u64,
+    quota: Option<&'static DiskQuota>,
+    exceeded: bool,
+}
+
+impl QuotaCharge {
+    /// Wait until the quota has room left before starting to charge it.
+    /// Fails at once when only extracted directories use it up, since no
+    /// running charge would release anything.
+    pub async fn start() -> Result<QuotaCharge, QuotaExceeded> {
+        Self::start_on(DISK_QUOTA.get()).await
+    }
+
+    async fn start_on(quota: Option<&'static DiskQuota>) -> Result<QuotaCharge, QuotaExceeded> {
+        if let Some(quota) = quota {
+            loop {
+                let released = quota.released.notified();
+                tokio::pin!(released);
+                // register first so a release between the check and the wait
+                // is not missed
+                released.as_mut().enable();
+                {
+                    let mut state = quota.state.lock().unwrap();
+                    if state.used < quota.limit {
+                        state.in_flight += 1;
+                        break;
+                    }
+                    if state.in_flight == 0 {
+                        return Err(QuotaExceeded(quota.limit));
+                    }
+                }
+                released.await;
+            }
+        }
+        Ok(QuotaCharge {
+            bytes: 0,
+            quota,
+            exceeded: false,
+        })
+    }
+
+    /// Charge `bytes` more, failing when the quota would be exceeded
+    pub fn add(&mut self, bytes: u64) -> Result<(), QuotaExceeded> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+        let mut state = quota.state.lock().unwrap();
+        if state.used + bytes > quota.limit {
+            self.exceeded = true;
+            return Err(QuotaExceeded(quota.limit));
+        }
+        state.used += bytes;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// The error of an `add` that failed
+    fn exceeded(&self) -> Option<QuotaExceeded> {
+        self.quota
+            .filter(|_| self.exceeded)
+            .map(|quota| QuotaExceeded(quota.limit))
+    }
+
+    /// Return `bytes` of the charge, e.g. for a file that was removed
+    fn release(&mut self, bytes: u64) {
+        let Some(quota) = self.quota else {
+            return;
+        };
+        let bytes = bytes.min(self.bytes);
+        quota.state.lock().unwrap().used -= bytes;
+        self.bytes -= bytes;
+        quota.released.notify_waiters();
+    }
+
+    /// Keep the charge until `release_disk_quota` is called for `dir`
+    fn hold(mut self, dir: &Path) {
+        let Some(quota) = self.quota.take() else {
+            return;
+        };
+        let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let mut state = quota.state.lock().unwrap();
+        *state.held.entry(dir).or_default() += self.bytes;
+        state.in_flight -= 1;
+        // waiters recheck, they may now have nothing left to wait for
+        quota.released.notify_waiters();
+    }
+}
+
+impl Drop for QuotaCharge {
+    fn drop(&mut self) {
+        if let Some(quota) = self.quota {
+            let mut state = quota.state.lock().unwrap();
+            state.used -= self.bytes;
+            state.in_flight -= 1;
+            quota.released.notify_waiters();
+        }
+    }
+}
+
+/// Reads through `inner`, charging every byte read to the disk quota before
+/// it is written out
+struct QuotaReader<'a, R> {
+    inner: R,
+    charge: &'a mut QuotaCharge,
+}
+
+impl<R: std::io::Read> std::io::Read for QuotaReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.charge
+            .add(read as u64)
+            .map_err(std::io::Error::other)?;
+        Ok(read)
+    }
+}
+
+/// Attempts made to finish a download before giving up
+const DOWNLOAD_ATTEMPTS: usize = 3;
+
+#[derive(Default)]
+struct DownloadProgress {
+    written: u64,
+    total: Option<u64>,
+    resumable: bool,
+}
 
+/// Download `url` into `path`, resuming with range requests when interrupted
+async fn download_resumable(
+    url: &str,
+    path: &Path,
+    charge: &mut QuotaCharge,
+) -> Result<(), Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    let mut file = File::create(path)?;
+    let mut progress = DownloadProgress::default();
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        match fetch_range(&client, url, &mut file, &mut progress, charge).await {
+            Ok(()) => break,
+            // resuming cannot make room in the quota
+            Err(e)
+                if attempt < DOWNLOAD_ATTEMPTS
+                    && progress.resumable
+                    && !e.is::<QuotaExceeded>() =>
+            {
+                warn!(
+                    "Download of {} interrupted at {} bytes, resuming: {}",
+                    url, progress.written, e
+                );
+            }
+            Err(e) => return Err(format!("Failed to download {}: {}", url, e).into()),
+        }
+    }
+
+    if let Some(total) = progress.total {
+        if progress.written != total {
+            return Err(format!(
+                "Downloaded {} bytes from {}, expected {}",
+                progress.written, url, total
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Fetch the rest of `url` starting at the bytes already written
+async fn fetch_range(
+    client: &reqwest::Client,
+    url: &str,
+    file: &mut File,
+    progress: &mut DownloadProgress,
+    charge: &mut QuotaCharge,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut request = client.get(url);
+    if progress.written > 0 {
+        request = request.header(RANGE, format!("bytes={}-", progress.written));
+    }
+    let mut response = request.send().await?.error_for_status()?;
+
+    // the server ignored the range, so start over from scratch
+    if progress.written > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+        charge.release(progress.written);
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        progress.written = 0;
+    }
+    if progress.written == 0 {
+        progress.resumable = response
+            .headers()
+            .get(ACCEPT_RANGES)
+            .is_some_and(|value| value.as_bytes() == b"bytes");
+        progress.total = response.content_length();
+    }
+
+    while let Some(chunk) = response.chunk().await? {
+        charge.add(chunk.len() as u64)?;
+        file.write_all(&chunk)?;
+        progress.written += chunk.len() as u64;
+    }
     Ok(())
 }
 
@@ -85,22 +675,1711 @@ async fn diff_file_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
     let base = args.first().ok_or("Base file not set")?;
     let submission = args.get(1).ok_or("Submission file not set")?;
     let count = args.get(2).ok_or("Count not specify")?;
+    let ignore = match args.get(3).map(String::as_str) {
+        None => None,
+        Some("--ignore-lines") => Some(Regex::new(
+            args.get(4).ok_or("Ignored lines regex not set")?,
+        )?),
+        Some(flag) => return Err(format!("Unknown diff_file flag {}", flag).into()),
+    };
 
-    let diff = diff_file(base, submission).await?;
-    if diff > count.parse()? {
-        return Err(format!("Diff count {} is greater than {}", diff, count).into());
+    let pairs = if is_glob(base) || is_glob(submission) {
+        glob_pairs(base, submission)?
+    } else {
+        vec![(base.clone(), submission.clone())]
+    };
+    let (mut diff, mut base_lines) = (0, 0);
+    for (base, submission) in pairs {
+        let old = kept_lines(&read_to_string(&base)?, ignore.as_ref());
+        let new = kept_lines(&read_to_string(&submission)?, ignore.as_ref());
+        diff += line_diff_count(&old, &new);
+        base_lines += old.lines().count();
+    }
+
+    let limit = diff_limit(count, base_lines)?;
+    if diff > limit {
+        let limit = match count.strip_suffix('%') {
+            Some(_) => format!("{} ({} of {} lines)", limit, count, base_lines),
+            None => limit.to_string(),
+        };
+        return Err(format!("Diff count {} is greater than {}", diff, limit).into());
     }
 
     Ok(())
 }
 
+/// Allowed changed lines for a threshold given as a count or, like `5%`, as
+/// a percentage of the base file's lines
+fn diff_limit(threshold: &str, base_lines: usize) -> Result<usize, Box<dyn Error>> {
+    match threshold.strip_suffix('%') {
+        Some(percent) => {
+            let percent: f64 = percent.trim().parse()?;
+            Ok((base_lines as f64 * percent / 100.0).floor() as usize)
+        }
+        None => Ok(threshold.parse()?),
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Expand both patterns and pair the matches by file stem, so that
+/// `out/*.expected` pairs `out/a.expected` with `build/a.out`
+fn glob_pairs(base: &str, submission: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let by_stem = |pattern: &str| -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let mut files = HashMap::new();
+        for path in glob::glob(pattern)? {
+            let path = path?;
+            let stem = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            files.insert(stem, path.to_string_lossy().into_owned());
+        }
+        Ok(files)
+    };
+    let bases = by_stem(base)?;
+    let mut submissions = by_stem(submission)?;
+    if bases.is_empty() {
+        return Err(format!("No files match {}", base).into());
+    }
+
+    let mut pairs = Vec::new();
+    let mut missing = Vec::new();
+    for (stem, base) in bases {
+        match submissions.remove(&stem) {
+            Some(submission) => pairs.push((base, submission)),
+            None => missing.push(base),
+        }
+    }
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(format!("No submission file for {}", missing.join(", ")).into());
+    }
+    pairs.sort();
+    Ok(pairs)
+}
+
 /// return: line diff count
 async fn diff_file(base: &str, submission: &str) -> Result<usize, Box<dyn Error>> {
     let old = read_to_string(base)?;
     let new = read_to_string(submission)?;
-    let diff = TextDiff::from_lines(&old, &new)
+    Ok(line_diff_count(&old, &new))
+}
+
+/// `text` without the lines matching `ignore`, such as timestamps
+fn kept_lines(text: &str, ignore: Option<&Regex>) -> String {
+    let Some(ignore) = ignore else {
+        return text.to_string();
+    };
+    text.lines()
+        .filter(|line| !ignore.is_match(line))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+fn line_diff_count(old: &str, new: &str) -> usize {
+    TextDiff::from_lines(old, new)
         .iter_all_changes()
         .filter(|change| change.tag() != ChangeTag::Equal)
-        .count();
-    Ok(diff)
+        .count()
+}
+
+async fn diff_numeric_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let base = args.first().ok_or("Base file not set")?;
+    let submission = args.get(1).ok_or("Submission file not set")?;
+    let abs_tol: f64 = args.get(2).ok_or("Absolute tolerance not set")?.parse()?;
+    let rel_tol: f64 = args.get(3).ok_or("Relative tolerance not set")?.parse()?;
+    diff_numeric(base, submission, abs_tol, rel_tol)
+}
+
+/// Compare whitespace separated tokens, numbers within `abs_tol` or
+/// `rel_tol` of the base value and anything else exactly
+fn diff_numeric(
+    base: &str,
+    submission: &str,
+    abs_tol: f64,
+    rel_tol: f64,
+) -> Result<(), Box<dyn Error>> {
+    let old = read_to_string(base)?;
+    let new = read_to_string(submission)?;
+    let tokens = |text: &str| -> Vec<(usize, String)> {
+        text.lines()
+            .enumerate()
+            .flat_map(|(line, s)| s.split_whitespace().map(move |t| (line + 1, t.to_string())))
+            .collect()
+    };
+    let (expected, actual) = (tokens(&old), tokens(&new));
+
+    for ((line, want), (_, got)) in expected.iter().zip(&actual) {
+        let matches = match (want.parse::<f64>(), got.parse::<f64>()) {
+            (Ok(want), Ok(got)) => {
+                let diff = (want - got).abs();
+                diff <= abs_tol || diff <= rel_tol * want.abs()
+            }
+            _ => want == got,
+        };
+        if !matches {
+            return Err(format!("Line {}: expected {}, got {}", line, want, got).into());
+        }
+    }
+    if expected.len() != actual.len() {
+        return Err(format!("Expected {} values, got {}", expected.len(), actual.len()).into());
+    }
+    Ok(())
+}
+
+/// Files listed when `diff_files` goes over its threshold
+const MAX_REPORTED_DIFFS: usize = 5;
+
+async fn diff_files_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let base = args.first().ok_or("Base dir not set")?;
+    let submission = args.get(1).ok_or("Submission dir not set")?;
+    let count = args.get(2).ok_or("Count not specify")?;
+
+    let mut diffs = diff_files(Path::new(base), Path::new(submission)).await?;
+    let total: usize = diffs.iter().map(|(_, diff)| diff).sum();
+    if total > count.parse()? {
+        diffs.sort_by_key(|(_, diff)| std::cmp::Reverse(*diff));
+        let worst = diffs
+            .iter()
+            .filter(|(_, diff)| *diff > 0)
+            .take(MAX_REPORTED_DIFFS)
+            .map(|(file, diff)| format!("{}: {}", file.display(), diff))
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err(format!("Diff count {} is greater than {}\n{}", total, count, worst).into());
+    }
+
+    Ok(())
+}
+
+/// Diff every file under `base` against the same relative path under
+/// `submission`, concurrently. A missing submission file counts as empty.
+/// return: line diff count of each base file, relative to `base`
+async fn diff_files(
+    base: &Path,
+    submission: &Path,
+) -> Result<Vec<(PathBuf, usize)>, Box<dyn Error>> {
+    let files = collect_files(base, &[])?;
+    let tasks = files.into_iter().map(|file| {
+        let relative = file.strip_prefix(base).unwrap_or(&file).to_path_buf();
+        let other = submission.join(&relative);
+        tokio::task::spawn_blocking(move || {
+            let old = read_to_string(&file)?;
+            let new = read_to_string(&other).unwrap_or_default();
+            Ok::<_, std::io::Error>((relative, line_diff_count(&old, &new)))
+        })
+    });
+    let mut diffs = Vec::new();
+    for diff in futures::future::join_all(tasks).await {
+        diffs.push(diff??);
+    }
+    Ok(diffs)
+}
+
+async fn diff_remote_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let url = args.first().ok_or("Reference URL not set")?;
+    let submission = args.get(1).ok_or("Submission file not set")?;
+    let count = args.get(2).ok_or("Count not specify")?;
+    let header = args.get(3).map(String::as_str);
+
+    let reference = download_temp_path("ref");
+    if let Err(e) = download_reference(url, header, &reference).await {
+        let _ = std::fs::remove_file(&reference);
+        return Err(e);
+    }
+    let diff = diff_file(&reference.to_string_lossy(), submission).await;
+    std::fs::remove_file(&reference)?;
+    let diff = diff?;
+    if diff > count.parse()? {
+        return Err(format!("Diff count {} is greater than {}", diff, count).into());
+    }
+
+    Ok(())
+}
+
+/// Download a reference file, `header` is an optional `Name: value` pair
+async fn download_reference(
+    url: &str,
+    header: Option<&str>,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(header) = header {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or("Header must be in the form `Name: value`")?;
+        request = request.header(name.trim(), value.trim());
+    }
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to download reference {}: {}", url, e))?;
+    std::fs::write(path, response.bytes().await?)?;
+    Ok(())
+}
+
+const CPP_EXTENSIONS: &[&str] = &["c", "cc", "cpp", "cxx", "h", "hh", "hpp", "hxx"];
+
+/// Collect files under `path` (or `path` itself) with one of `extensions`,
+/// or every file when `extensions` is empty
+fn collect_files(path: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if entry_path.is_dir() {
+            files.extend(collect_files(&entry_path, extensions)?);
+        } else if extensions.is_empty()
+            || entry_path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| extensions.contains(&ext))
+        {
+            files.push(entry_path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+async fn check_format_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let target = args.first().ok_or("File or directory not set")?;
+    let style = args.get(1).map(String::as_str).unwrap_or("file");
+    check_format(target, style).await
+}
+
+async fn check_format(target: &str, style: &str) -> Result<(), Box<dyn Error>> {
+    let files = collect_files(Path::new(target), CPP_EXTENSIONS)?;
+    let mut diffs = String::new();
+    for file in files {
+        let output = tokio::process::Command::new("clang-format")
+            .arg(format!("--style={}", style))
+            .arg(&file)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(format!(
+                "clang-format failed on {}: {}",
+                file.display(),
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+
+        let original = read_to_string(&file)?;
+        let formatted = String::from_utf8_lossy(&output.stdout);
+        if original != formatted {
+            let name = file.display().to_string();
+            diffs.push_str(
+                &TextDiff::from_lines(original.as_str(), formatted.as_ref())
+                    .unified_diff()
+                    .header(&name, &format!("{} (formatted)", name))
+                    .to_string(),
+            );
+        }
+    }
+
+    if !diffs.is_empty() {
+        return Err(format!("Code is not formatted with style '{}':\n{}", style, diffs).into());
+    }
+    Ok(())
+}
+
+/// Findings quoted in a failure message before it gets truncated
+const MAX_REPORTED_FINDINGS: usize = 10;
+
+/// The first `MAX_REPORTED_FINDINGS` of `items`, one per line, noting how
+/// many more were left out
+fn summarize_findings(items: &[String]) -> String {
+    let mut summary = items[..items.len().min(MAX_REPORTED_FINDINGS)].join("\n");
+    if items.len() > MAX_REPORTED_FINDINGS {
+        summary.push_str(&format!(
+            "\n... and {} more",
+            items.len() - MAX_REPORTED_FINDINGS
+        ));
+    }
+    summary
+}
+
+async fn run_clang_tidy_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let build_dir = args.first().ok_or("compile_commands dir not set")?;
+    let checks = args.get(1).ok_or("Checks not set")?;
+    let threshold = match args.get(2) {
+        Some(threshold) => threshold.parse()?,
+        None => 0,
+    };
+
+    let findings = run_clang_tidy(build_dir, checks).await?;
+    if findings.len() > threshold {
+        let summary = summarize_findings(&findings);
+        return Err(format!(
+            "clang-tidy reported {} findings (allowed {}):\n{}",
+            findings.len(),
+            threshold,
+            summary
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// return: warning/error lines reported by clang-tidy
+async fn run_clang_tidy(build_dir: &str, checks: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let database = read_to_string(Path::new(build_dir).join("compile_commands.json"))?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&database)?;
+    let files: Vec<String> = entries
+        .iter()
+        .filter_map(|entry| entry.get("file")?.as_str().map(String::from))
+        .collect();
+    if files.is_empty() {
+        return Err("No source files found in compile_commands.json".into());
+    }
+
+    let output = tokio::process::Command::new("clang-tidy")
+        .arg("-p")
+        .arg(build_dir)
+        .arg(format!("--checks={}", checks))
+        .arg("--quiet")
+        .args(&files)
+        .output()
+        .await?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let findings: Vec<String> = stdout
+        .lines()
+        .filter(|line| line.contains(": warning:") || line.contains(": error:"))
+        .map(String::from)
+        .collect();
+    // findings make clang-tidy exit non-zero too, but without any it failed
+    // to run at all, e.g. on bad checks or a broken compile database
+    if !output.status.success() && findings.is_empty() {
+        return Err(format!(
+            "clang-tidy failed with {}\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )
+        .into());
+    }
+    Ok(findings)
+}
+
+async fn validate_json_schema_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let instance = args.first().ok_or("JSON file not set")?;
+    let schema = args.get(1).ok_or("Schema file not set")?;
+    validate_json_schema(Path::new(instance), Path::new(schema))
+}
+
+/// Check `instance` against the JSON Schema `schema`, failing with every
+/// violation found
+fn validate_json_schema(instance: &Path, schema: &Path) -> Result<(), Box<dyn Error>> {
+    let schema: serde_json::Value = serde_json::from_str(&read_to_string(schema)?)
+        .map_err(|e| format!("Invalid schema {}: {}", schema.display(), e))?;
+    let validator = jsonschema::validator_for(&schema)?;
+    let value: serde_json::Value = serde_json::from_str(&read_to_string(instance)?)
+        .map_err(|e| format!("{} is not valid JSON: {}", instance.display(), e))?;
+
+    let errors: Vec<String> = validator
+        .iter_errors(&value)
+        .map(|error| format!("at '{}': {}", error.instance_path, error))
+        .collect();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let summary = summarize_findings(&errors);
+    Err(format!(
+        "{} does not match the schema ({} errors):\n{}",
+        instance.display(),
+        errors.len(),
+        summary
+    )
+    .into())
+}
+
+async fn compile_with_flags_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let dir = args.first().ok_or("Project dir not set")?;
+    let flags = args.get(1).ok_or("Compiler flags not set")?;
+    compile_with_flags(Path::new(dir), flags).await
+}
+
+/// Build the CMake or Make project in `dir` with `flags` as its C and C++
+/// flags, CMake ones in a temporary build dir, failing with the compiler
+/// warnings if any were printed
+async fn compile_with_flags(dir: &Path, flags: &str) -> Result<(), Box<dyn Error>> {
+    let mut steps = Vec::new();
+    let mut build_dir = None;
+    if dir.join("CMakeLists.txt").exists() {
+        // a build dir shipped with the submission would keep the flags of its
+        // CMakeCache.txt, so configure a fresh one with the flags set
+        let fresh = download_temp_path("build");
+        let mut configure = tokio::process::Command::new("cmake");
+        configure
+            .arg("-S")
+            .arg(dir)
+            .arg("-B")
+            .arg(&fresh)
+            .arg(format!("-DCMAKE_C_FLAGS={}", flags))
+            .arg(format!("-DCMAKE_CXX_FLAGS={}", flags));
+        let mut build = tokio::process::Command::new("cmake");
+        build.arg("--build").arg(&fresh);
+        steps.push(configure);
+        steps.push(build);
+        build_dir = Some(fresh);
+    } else if dir.join("Makefile").exists() || dir.join("makefile").exists() {
+        // variables given on the command line override the Makefile's own
+        let mut make = tokio::process::Command::new("make");
+        make.arg("-C")
+            .arg(dir)
+            .arg(format!("CFLAGS={}", flags))
+            .arg(format!("CXXFLAGS={}", flags));
+        steps.push(make);
+    } else {
+        return Err(format!("No CMakeLists.txt or Makefile in {}", dir.display()).into());
+    }
+
+    let result = run_compile_steps(steps, flags).await;
+    if let Some(build_dir) = build_dir {
+        let _ = std::fs::remove_dir_all(build_dir);
+    }
+    result
+}
+
+/// Run the build `steps` in order, failing with the compiler warnings if
+/// any were printed
+async fn run_compile_steps(
+    steps: Vec<tokio::process::Command>,
+    flags: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut warnings = Vec::new();
+    for mut step in steps {
+        let output = step.kill_on_drop(true).output().await?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(format!(
+                "Compilation with '{}' failed with {}\n{}",
+                flags, output.status, stderr
+            )
+            .into());
+        }
+        warnings.extend(
+            stderr
+                .lines()
+                .filter(|line| line.contains("warning:"))
+                .map(String::from),
+        );
+    }
+    if !warnings.is_empty() {
+        return Err(format!(
+            "Compilation with '{}' printed {} warnings:\n{}",
+            flags,
+            warnings.len(),
+            warnings.join("\n")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+struct SubmissionSize {
+    files: usize,
+    bytes: u64,
+    largest: Option<(PathBuf, u64)>,
+}
+
+impl SubmissionSize {
+    fn add_file(&mut self, path: PathBuf, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+        if self.largest.as_ref().is_none_or(|(_, max)| size > *max) {
+            self.largest = Some((path, size));
+        }
+    }
+}
+
+async fn check_submission_size_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let dir = args.first().ok_or("Directory not set")?;
+    let max_files: usize = args.get(1).ok_or("Max file count not set")?.parse()?;
+    let max_bytes: u64 = args.get(2).ok_or("Max total bytes not set")?.parse()?;
+
+    let mut size = SubmissionSize {
+        files: 0,
+        bytes: 0,
+        largest: None,
+    };
+    submission_size(Path::new(dir), &mut size)?;
+    if size.files > max_files || size.bytes > max_bytes {
+        let largest = size
+            .largest
+            .map(|(path, bytes)| format!("\nLargest file: {} ({} bytes)", path.display(), bytes))
+            .unwrap_or_default();
+        return Err(format!(
+            "Submission has {} files totalling {} bytes (allowed {} files, {} bytes){}",
+            size.files, size.bytes, max_files, max_bytes, largest
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn submission_size(dir: &Path, size: &mut SubmissionSize) -> Result<(), Box<dyn Error>> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            submission_size(&entry.path(), size)?;
+        } else {
+            size.add_file(entry.path(), metadata.len());
+        }
+    }
+    Ok(())
+}
+
+async fn run_limited_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mem_bytes: u64 = args.first().ok_or("Memory limit not set")?.parse()?;
+    let time_secs: u64 = args.get(1).ok_or("Time limit not set")?.parse()?;
+    let exe = args.get(2).ok_or("Executable not set")?;
+    run_limited(mem_bytes, time_secs, exe, &args[3..]).await
+}
+
+/// Run `exe` with its address space capped at `mem_bytes` and its runtime
+/// capped at `time_secs`
+async fn run_limited(
+    mem_bytes: u64,
+    time_secs: u64,
+    exe: &str,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let mut command = tokio::process::Command::new(exe);
+    command.args(args).kill_on_drop(true);
+    #[cfg(unix)]
+    // SAFETY: the closure only calls the async-signal-safe `setrlimit`
+    unsafe {
+        command.pre_exec(move || {
+            let limit = libc::rlimit {
+                rlim_cur: mem_bytes as libc::rlim_t,
+                rlim_max: mem_bytes as libc::rlim_t,
+            };
+            if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let output = match tokio::time::timeout(Duration::from_secs(time_secs), command.output()).await
+    {
+        Ok(output) => output?,
+        Err(_) => return Err(format!("{} exceeded the time limit of {}s", exe, time_secs).into()),
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+
+    // only an allocation failure reported on stderr proves RLIMIT_AS was hit,
+    // a bare SIGSEGV or SIGABRT is just as likely a bug or a failed assert
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let out_of_memory = stderr.contains("bad_alloc")
+        || stderr.contains("Cannot allocate memory")
+        || stderr.contains("memory allocation");
+    if out_of_memory {
+        return Err(format!(
+            "{} exceeded the memory limit of {} bytes\n{}",
+            exe, mem_bytes, stderr
+        )
+        .into());
+    }
+    // the status names the signal, e.g. `signal: 11 (SIGSEGV)`
+    Err(format!("{} failed with {}\n{}", exe, output.status, stderr).into())
+}
+
+async fn run_expect_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let split = args
+        .iter()
+        .position(|arg| arg == "--expect")
+        .ok_or("--expect not set")?;
+    let (exe, exe_args) = args[..split].split_first().ok_or("Executable not set")?;
+    let pattern = args.get(split + 1).ok_or("Expected regex not set")?;
+    run_expect(exe, exe_args, &Regex::new(pattern)?).await
+}
+
+/// Longest slice of stdout quoted when the expected output is missing
+const MAX_QUOTED_OUTPUT: usize = 1024;
+
+/// Run `exe` and check that its stdout matches `expect`
+async fn run_expect(exe: &str, args: &[String], expect: &Regex) -> Result<(), Box<dyn Error>> {
+    let mut command = tokio::process::Command::new(exe);
+    command
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+    // dropping the timed out future kills the child
+    let output = match tokio::time::timeout(SCRIPT_TIMEOUT, command.output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            return Err(format!(
+                "{} exceeded the time limit of {}s without printing `{}`",
+                exe,
+                SCRIPT_TIMEOUT.as_secs(),
+                expect.as_str()
+            )
+            .into())
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if expect.is_match(&stdout) {
+        return Ok(());
+    }
+
+    let mut quoted: String = stdout.chars().take(MAX_QUOTED_OUTPUT).collect();
+    if quoted.len() < stdout.len() {
+        quoted.push_str("...");
+    }
+    Err(format!(
+        "Output of {} does not match `{}`\n{}",
+        exe,
+        expect.as_str(),
+        quoted
+    )
+    .into())
+}
+
+async fn run_deterministic_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let exe = args.first().ok_or("Executable not set")?;
+    let runs: usize = args.get(1).ok_or("Run count not set")?.parse()?;
+    if runs < 2 {
+        return Err("run_deterministic needs at least 2 runs".into());
+    }
+    run_deterministic(exe, &args[2..], runs).await
+}
+
+/// Run `exe` `runs` times and fail at the first run that fails or whose
+/// stdout differs from the first one, e.g. because of a data race
+async fn run_deterministic(exe: &str, args: &[String], runs: usize) -> Result<(), Box<dyn Error>> {
+    let mut first: Option<String> = None;
+    for run in 1..=runs {
+        let mut command = tokio::process::Command::new(exe);
+        command
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true);
+        let output = match tokio::time::timeout(SCRIPT_TIMEOUT, command.output()).await {
+            Ok(output) => output?,
+            Err(_) => {
+                return Err(format!(
+                    "Run {} of {} exceeded the time limit of {}s",
+                    run,
+                    exe,
+                    SCRIPT_TIMEOUT.as_secs()
+                )
+                .into())
+            }
+        };
+        // crashing the same way every run is no pass
+        if !output.status.success() {
+            return Err(format!(
+                "Run {} of {} failed with {}\n{}",
+                run,
+                exe,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            )
+            .into());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let Some(expected) = &first else {
+            first = Some(stdout);
+            continue;
+        };
+        if stdout != *expected {
+            let diff = TextDiff::from_lines(expected.as_str(), stdout.as_str())
+                .unified_diff()
+                .header("run 1", &format!("run {}", run))
+                .to_string();
+            let mut quoted: String = diff.chars().take(MAX_QUOTED_OUTPUT).collect();
+            if quoted.len() < diff.len() {
+                quoted.push_str("...");
+            }
+            return Err(format!(
+                "Output of {} changed in run {} of {}:\n{}",
+                exe, run, runs, quoted
+            )
+            .into());
+        }
+    }
+    info!("{} printed the same output in all {} runs", exe, runs);
+    Ok(())
+}
+
+async fn check_cpu_time_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let budget_secs: f64 = args.first().ok_or("CPU time budget not set")?.parse()?;
+    let exe = args.get(1).ok_or("Executable not set")?;
+    check_cpu_time(budget_secs, exe, &args[2..]).await
+}
+
+/// Run `exe` and fail if its user plus system CPU time exceeds `budget_secs`,
+/// however long it takes on the wall clock, up to a ceiling for programs
+/// that block on input or sleep
+async fn check_cpu_time(
+    budget_secs: f64,
+    exe: &str,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+    let mut command = std::process::Command::new(exe);
+    command.args(args).stdin(std::process::Stdio::null());
+    // stop runaway loops a second past the budget
+    let ceiling = budget_secs.ceil() as libc::rlim_t + 1;
+    // SAFETY: the closure only calls the async-signal-safe `setrlimit`
+    unsafe {
+        command.pre_exec(move || {
+            let limit = libc::rlimit {
+                rlim_cur: ceiling,
+                rlim_max: ceiling,
+            };
+            if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    let child = KillUnlessReaped {
+        pid: child.id() as libc::pid_t,
+        reaped: Arc::new(std::sync::Mutex::new(false)),
+    };
+    let (pid, reaped) = (child.pid, child.reaped.clone());
+    let mut wait = tokio::task::spawn_blocking(move || wait_with_rusage(pid, &reaped));
+    let wall_limit = SCRIPT_TIMEOUT.max(Duration::from_secs(2 * ceiling));
+    let (status, usage) = match tokio::time::timeout(wall_limit, &mut wait).await {
+        Ok(waited) => waited??,
+        Err(_) => {
+            child.kill();
+            wait.await??;
+            return Err(format!(
+                "{} exceeded the wall-clock limit of {}s",
+                exe,
+                wall_limit.as_secs()
+            )
+            .into());
+        }
+    };
+
+    let seconds = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1e6;
+    let cpu_secs = seconds(usage.ru_utime) + seconds(usage.ru_stime);
+    if cpu_secs > budget_secs {
+        return Err(format!(
+            "{} used {:.2}s of CPU time, over the budget of {}s",
+            exe, cpu_secs, budget_secs
+        )
+        .into());
+    }
+    let status = std::process::ExitStatus::from_raw(status);
+    if !status.success() {
+        return Err(format!("{} failed with {}", exe, status).into());
+    }
+    Ok(())
+}
+
+/// Kills a child of `check_cpu_time` that is not reaped yet when dropped,
+/// e.g. because a step timeout dropped the builtin
+struct KillUnlessReaped {
+    pid: libc::pid_t,
+    /// Set by `wait_with_rusage` once `pid` may belong to another process
+    reaped: Arc<std::sync::Mutex<bool>>,
+}
+
+impl KillUnlessReaped {
+    fn kill(&self) {
+        let reaped = self.reaped.lock().unwrap();
+        if !*reaped {
+            // SAFETY: holding the lock keeps `pid` unreaped, so it is our child
+            unsafe { libc::kill(self.pid, libc::SIGKILL) };
+        }
+    }
+}
+
+impl Drop for KillUnlessReaped {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Reap `pid` and return its wait status along with its resource usage,
+/// setting `reaped` in the same critical section
+fn wait_with_rusage(
+    pid: libc::pid_t,
+    reaped: &std::sync::Mutex<bool>,
+) -> std::io::Result<(i32, libc::rusage)> {
+    // wait without reaping first, so the pid cannot be reused while a kill
+    // may still be sent to it
+    // SAFETY: `siginfo_t` is plain old data, zeroed is a valid value
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: `info` is valid for the duration of the call
+        let waited = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if waited == 0 {
+            break;
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    let mut reaped = reaped.lock().unwrap();
+    let mut status = 0;
+    // SAFETY: `rusage` is plain old data, zeroed is a valid value
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call
+    if unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    *reaped = true;
+    Ok((status, usage))
+}
+
+async fn diff_tree_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let reference = args.first().ok_or("Reference dir not set")?;
+    let produced = args.get(1).ok_or("Produced dir not set")?;
+    let allowed = args[2..]
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+    let problems = diff_tree(Path::new(reference), Path::new(produced), &allowed)?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let summary = summarize_findings(&problems);
+    Err(format!(
+        "{} differs from the expected layout:\n{}",
+        produced, summary
+    )
+    .into())
+}
+
+/// Compare the file names under `reference` and `produced`, ignoring
+/// contents and any relative path matching one of `allowed`.
+/// return: one line per missing or extra file
+fn diff_tree(
+    reference: &Path,
+    produced: &Path,
+    allowed: &[glob::Pattern],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let relative = |root: &Path| -> Result<HashSet<PathBuf>, Box<dyn Error>> {
+        collect_files(root, &[])?
+            .into_iter()
+            .map(|path| Ok(path.strip_prefix(root)?.to_path_buf()))
+            .collect()
+    };
+    let expected = relative(reference)?;
+    let actual = relative(produced)?;
+    let is_allowed = |path: &Path| allowed.iter().any(|pattern| pattern.matches_path(path));
+
+    let mut problems: Vec<String> = expected
+        .difference(&actual)
+        .filter(|path| !is_allowed(path))
+        .map(|path| format!("missing {}", path.display()))
+        .chain(
+            actual
+                .difference(&expected)
+                .filter(|path| !is_allowed(path))
+                .map(|path| format!("unexpected {}", path.display())),
+        )
+        .collect();
+    problems.sort();
+    Ok(problems)
+}
+
+async fn check_archive_structure_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let (dir, required) = args.split_first().ok_or("Extracted dir not set")?;
+    let problems = check_archive_structure(Path::new(dir), required)?;
+    if !problems.is_empty() {
+        return Err(format!("Unexpected archive structure:\n{}", problems.join("\n")).into());
+    }
+    Ok(())
+}
+
+/// Extensions of archives that should have been extracted, not nested
+const ARCHIVE_EXTENSIONS: &[&str] = &["7z", "zip", "rar", "tar", "gz", "tgz", "xz", "bz2"];
+
+/// Check `dir` holds every path in `required`, where a trailing `/` asks
+/// for a directory, and no nested archives.
+/// return: one line per problem found
+fn check_archive_structure(dir: &Path, required: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut problems = Vec::new();
+
+    // a single top-level folder usually means the wrong folder was zipped
+    let entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let wrapper = match entries.as_slice() {
+        [only] if only.is_dir() => Some(only),
+        _ => None,
+    };
+
+    for path in required {
+        let want_dir = path.ends_with('/');
+        let target = dir.join(path.trim_end_matches('/'));
+        let found = if want_dir {
+            target.is_dir()
+        } else {
+            target.is_file()
+        };
+        if found {
+            continue;
+        }
+        let kind = if want_dir { "directory" } else { "file" };
+        let mut problem = format!("missing {} `{}`", kind, path);
+        if let Some(wrapper) = wrapper {
+            if wrapper.join(path.trim_end_matches('/')).exists() {
+                let name = wrapper.file_name().unwrap_or_default().to_string_lossy();
+                problem.push_str(&format!(
+                    " (found under `{}/`, zip its contents instead)",
+                    name
+                ));
+            }
+        }
+        problems.push(problem);
+    }
+
+    for archive in collect_files(dir, ARCHIVE_EXTENSIONS)? {
+        let archive = archive.strip_prefix(dir).unwrap_or(&archive);
+        problems.push(format!("nested archive `{}`", archive.display()));
+    }
+    Ok(problems)
+}
+
+async fn run_script_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    // leading `NAME=value` arguments become the script's environment
+    let split = args
+        .iter()
+        .position(|arg| !arg.contains('='))
+        .ok_or("Script path not set")?;
+    let env = args[..split]
+        .iter()
+        .filter_map(|arg| arg.split_once('='))
+        .collect::<Vec<_>>();
+    run_script(&args[split], &args[split + 1..], &env).await
+}
+
+/// Longest a student script may run, steps can set a shorter `timeout`
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Run `script` with `sh` in a clean environment holding only `PATH` and
+/// `env`, failing with its interleaved stdout and stderr
+async fn run_script(
+    script: &str,
+    args: &[String],
+    env: &[(&str, &str)],
+) -> Result<(), Box<dyn Error>> {
+    let (mut reader, writer) = std::io::pipe()?;
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .arg(script)
+        .args(args)
+        .env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .envs(env.iter().copied())
+        .stdin(std::process::Stdio::null())
+        .stdout(writer.try_clone()?)
+        .stderr(writer)
+        // a group of its own, so whatever the script leaves running in the
+        // background can be killed with it
+        .process_group(0)
+        .kill_on_drop(true);
+    let mut child = command.spawn()?;
+    let group = KillGroup(child.id().ok_or("Script exited before it was tracked")? as i32);
+    // close our copies of the write end so the read sees EOF
+    drop(command);
+    let output = tokio::task::spawn_blocking(move || {
+        let mut output = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut output).map(|_| output)
+    });
+
+    let deadline = tokio::time::Instant::now() + SCRIPT_TIMEOUT;
+    let status = match tokio::time::timeout_at(deadline, child.wait()).await {
+        Ok(status) => status?,
+        Err(_) => {
+            return Err(format!(
+                "{} exceeded the time limit of {}s",
+                script,
+                SCRIPT_TIMEOUT.as_secs()
+            )
+            .into());
+        }
+    };
+    // background processes would otherwise keep the output pipe open
+    drop(group);
+    if status.success() {
+        return Ok(());
+    }
+    let output = match tokio::time::timeout_at(deadline, output).await {
+        Ok(output) => output??,
+        Err(_) => {
+            return Err(format!(
+                "{} failed with {} and its output was not closed within {}s",
+                script,
+                status,
+                SCRIPT_TIMEOUT.as_secs()
+            )
+            .into())
+        }
+    };
+    Err(format!(
+        "{} failed with {}\n{}",
+        script,
+        status,
+        String::from_utf8_lossy(&output)
+    )
+    .into())
+}
+
+/// Kills the process group of a `run_script` script when dropped
+struct KillGroup(i32);
+
+impl Drop for KillGroup {
+    fn drop(&mut self) {
+        // SAFETY: kill has no memory safety requirements; the group only
+        // holds the script and the processes it started
+        unsafe { libc::kill(-self.0, libc::SIGKILL) };
+    }
+}
+
+async fn run_testcases_builtin(args: Vec<String>) -> Result<i64, Box<dyn Error>> {
+    let exe = args.first().ok_or("Executable not set")?;
+    let cases_dir = args.get(1).ok_or("Test case dir not set")?;
+    let as_arg = match args.get(2).map(String::as_str) {
+        None | Some("stdin") => false,
+        Some("arg") => true,
+        Some(mode) => return Err(format!("Unknown input mode {}", mode).into()),
+    };
+    let passed = run_testcases(exe, Path::new(cases_dir), as_arg).await?;
+    Ok(passed as i64)
+}
+
+/// Longest a single test case may run
+const TESTCASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `exe` on every `input_N` in `cases_dir`, on stdin or as its argument
+/// when `as_arg`, and compare its stdout with `expected_N` ignoring trailing
+/// whitespace.
+/// return: number of passing cases
+async fn run_testcases(exe: &str, cases_dir: &Path, as_arg: bool) -> Result<usize, Box<dyn Error>> {
+    let mut cases = Vec::new();
+    for entry in std::fs::read_dir(cases_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(case) = name.strip_prefix("input_") {
+            cases.push(case.to_string());
+        }
+    }
+    if cases.is_empty() {
+        return Err(format!("No input_N files in {}", cases_dir.display()).into());
+    }
+    cases.sort();
+
+    let mut passed = 0;
+    for case in &cases {
+        let input = cases_dir.join(format!("input_{}", case));
+        let expected = read_to_string(cases_dir.join(format!("expected_{}", case)))?;
+        let mut command = tokio::process::Command::new(exe);
+        if as_arg {
+            command.arg(&input).stdin(std::process::Stdio::null());
+        } else {
+            command.stdin(File::open(&input)?);
+        }
+        command.kill_on_drop(true);
+
+        let output = match tokio::time::timeout(TESTCASE_TIMEOUT, command.output()).await {
+            Ok(output) => output?,
+            Err(_) => {
+                warn!("Test case {} timed out", case);
+                continue;
+            }
+        };
+        let normalize = |text: &str| -> Vec<String> {
+            let lines: Vec<String> = text
+                .lines()
+                .map(|line| line.trim_end().to_string())
+                .collect();
+            let len = lines
+                .iter()
+                .rposition(|line| !line.is_empty())
+                .map_or(0, |i| i + 1);
+            lines[..len].to_vec()
+        };
+        if normalize(&String::from_utf8_lossy(&output.stdout)) == normalize(&expected) {
+            passed += 1;
+        } else {
+            warn!("Test case {} failed", case);
+        }
+    }
+    info!("Passed {} of {} test cases", passed, cases.len());
+    Ok(passed)
+}
+
+/// Counts `run_gtest` returns besides the pass count
+const GTEST_EXTRAS: &[&str] = &["passed", "failed", "disabled"];
+
+async fn run_gtest_builtin(args: Vec<String>) -> Result<BuiltinOutput, Box<dyn Error>> {
+    let binary = args.first().ok_or("Test binary not set")?;
+    let allow_failures = match args.get(1).map(String::as_str) {
+        None => false,
+        Some("--allow-failures") => true,
+        Some(flag) => return Err(format!("Unknown run_gtest flag {}", flag).into()),
+    };
+    let counts = run_gtest(binary).await?;
+    info!(
+        "{}: {} passed, {} failed, {} disabled",
+        binary,
+        counts.passed,
+        counts.failed.len(),
+        counts.disabled
+    );
+    if !counts.failed.is_empty() && !allow_failures {
+        let summary = summarize_findings(&counts.failed);
+        return Err(format!(
+            "{} of {} tests failed:\n{}",
+            counts.failed.len(),
+            counts.passed + counts.failed.len(),
+            summary
+        )
+        .into());
+    }
+    Ok(BuiltinOutput {
+        value: Some(counts.passed as i64),
+        extra: vec![
+            ("passed", counts.passed as i64),
+            ("failed", counts.failed.len() as i64),
+            ("disabled", counts.disabled as i64),
+        ],
+    })
+}
+
+#[derive(Default)]
+struct GtestCounts {
+    passed: usize,
+    /// `Suite.Test` names of the failed tests
+    failed: Vec<String>,
+    /// Disabled and skipped tests
+    disabled: usize,
+}
+
+/// Run a GoogleTest `binary` and count its results from the JSON report,
+/// which is sturdier than scraping its console output
+async fn run_gtest(binary: &str) -> Result<GtestCounts, Box<dyn Error>> {
+    let report = download_temp_path("json");
+    let mut command = tokio::process::Command::new(binary);
+    command
+        .arg(format!("--gtest_output=json:{}", report.display()))
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+    let output = match tokio::time::timeout(SCRIPT_TIMEOUT, command.output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            let _ = std::fs::remove_file(&report);
+            return Err(format!(
+                "{} exceeded the time limit of {}s",
+                binary,
+                SCRIPT_TIMEOUT.as_secs()
+            )
+            .into());
+        }
+    };
+    // a crashing test leaves no report behind
+    let Ok(contents) = read_to_string(&report) else {
+        return Err(format!(
+            "{} wrote no test report, it exited with {}\n{}",
+            binary,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    };
+    std::fs::remove_file(&report)?;
+    let report: serde_json::Value = serde_json::from_str(&contents)?;
+
+    let mut counts = GtestCounts::default();
+    let suites = report["testsuites"]
+        .as_array()
+        .ok_or("Report has no testsuites")?;
+    for suite in suites {
+        for test in suite["testsuite"].as_array().into_iter().flatten() {
+            let failed = test["failures"]
+                .as_array()
+                .is_some_and(|failures| !failures.is_empty());
+            if test["status"] == "NOTRUN" || test["result"] == "SKIPPED" {
+                counts.disabled += 1;
+            } else if failed {
+                counts.failed.push(format!(
+                    "{}.{}",
+                    suite["name"].as_str().unwrap_or_default(),
+                    test["name"].as_str().unwrap_or_default()
+                ));
+            } else {
+                counts.passed += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+async fn check_banned_tokens_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let (target, banned) = args.split_first().ok_or("Target not set")?;
+    if banned.is_empty() {
+        return Err("Banned tokens not set".into());
+    }
+
+    let mut findings = Vec::new();
+    let files = collect_files(Path::new(target), CPP_EXTENSIONS)?;
+    for file in files {
+        let source = read_to_string(&file)?;
+        for (line, identifier) in cpp_identifiers(&source) {
+            if banned.iter().any(|token| token == identifier) {
+                findings.push(format!("{}:{}: {}", file.display(), line, identifier));
+            }
+        }
+    }
+    if findings.is_empty() {
+        return Ok(());
+    }
+
+    let summary = summarize_findings(&findings);
+    Err(format!("Found {} banned calls:\n{}", findings.len(), summary).into())
+}
+
+/// Identifiers of C/C++ `source` with their line numbers, skipping comments
+/// and string or character literals
+fn cpp_identifiers(source: &str) -> Vec<(usize, &str)> {
+    let bytes = source.as_bytes();
+    let mut identifiers = Vec::new();
+    let (mut i, mut line) = (0, 1);
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < bytes.len() && !bytes[i..].starts_with(b"*/") {
+                    line += usize::from(bytes[i] == b'\n');
+                    i += 1;
+                }
+                i += 2;
+            }
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote && bytes[i] != b'\n' {
+                    // skip the escaped character, which may continue the line
+                    if bytes[i] == b'\\' {
+                        line += usize::from(bytes.get(i + 1) == Some(&b'\n'));
+                        i += 2;
+                    } else {
+                        i += 1;
+                    }
+                }
+                i += 1;
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                let word = &source[start..i];
+                // raw strings like `R"x(...)x"` end only at their delimiter
+                if matches!(word, "R" | "LR" | "uR" | "UR" | "u8R") && bytes.get(i) == Some(&b'"') {
+                    let open = i + 1;
+                    let Some(paren) = source[open..].find(['(', '\n']) else {
+                        break;
+                    };
+                    let closing = format!("){}\"", &source[open..open + paren]);
+                    let body = open + paren + 1;
+                    let end = source[body..]
+                        .find(&closing)
+                        .map_or(bytes.len(), |end| body + end + closing.len());
+                    line += source[open..end].matches('\n').count();
+                    i = end;
+                    continue;
+                }
+                identifiers.push((line, word));
+            }
+            // keep numbers like `0x1f`, `1e5` or `1'000` from producing
+            // identifiers or character literals
+            c if c.is_ascii_digit() => {
+                while i < bytes.len()
+                    && (bytes[i].is_ascii_alphanumeric()
+                        || bytes[i] == b'.'
+                        || (bytes[i] == b'\''
+                            && bytes.get(i + 1).is_some_and(u8::is_ascii_alphanumeric)))
+                {
+                    i += 1;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    identifiers
+}
+
+/// Leading lines searched for the header when `check_header` is given no count
+const DEFAULT_HEADER_LINES: usize = 10;
+
+async fn check_header_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let pattern = args.first().ok_or("File glob not set")?;
+    let required = Regex::new(args.get(1).ok_or("Required header regex not set")?)?;
+    let lines = match args.get(2) {
+        Some(lines) => lines.parse()?,
+        None => DEFAULT_HEADER_LINES,
+    };
+    check_header(pattern, &required, lines)
+}
+
+/// Check that the first `lines` lines of every file matching `pattern`
+/// match `required`, which may span several lines
+fn check_header(pattern: &str, required: &Regex, lines: usize) -> Result<(), Box<dyn Error>> {
+    let mut checked = 0;
+    let mut missing = Vec::new();
+    for path in glob::glob(pattern)? {
+        let path = path?;
+        let source = read_to_string(&path)?;
+        let header = source.lines().take(lines).collect::<Vec<_>>().join("\n");
+        if !required.is_match(&header) {
+            missing.push(path.display().to_string());
+        }
+        checked += 1;
+    }
+    if checked == 0 {
+        return Err(format!("No files match {}", pattern).into());
+    }
+    if !missing.is_empty() {
+        return Err(format!(
+            "{} of {} files lack the required header `{}` in their first {} lines:\n{}",
+            missing.len(),
+            checked,
+            required,
+            lines,
+            missing.join("\n")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn check_binary_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let (path, options) = args.split_first().ok_or("Binary path not set")?;
+    let mut max_size = None;
+    let mut require_static = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--max-size" => {
+                let size = options.next().ok_or("--max-size needs a byte count")?;
+                max_size = Some(size.parse::<u64>()?);
+            }
+            "--static" => require_static = true,
+            _ => return Err(format!("Unknown check_binary option {}", option).into()),
+        }
+    }
+
+    let data = std::fs::read(path)?;
+    let interpreter = elf_has_interpreter(&data).map_err(|e| format!("{}: {}", path, e))?;
+    let mut problems = Vec::new();
+    if let Some(max_size) = max_size {
+        if data.len() as u64 > max_size {
+            problems.push(format!(
+                "size is {} bytes, limit is {}",
+                data.len(),
+                max_size
+            ));
+        }
+    }
+    if require_static && interpreter {
+        problems.push("binary is dynamically linked, expected static".to_string());
+    }
+    if !problems.is_empty() {
+        return Err(format!("{}: {}", path, problems.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// Whether an ELF binary requests a program interpreter, i.e. the dynamic
+/// loader, which statically linked binaries do not
+fn elf_has_interpreter(data: &[u8]) -> Result<bool, Box<dyn Error>> {
+    const PT_INTERP: u32 = 3;
+    if data.get(..4) != Some(b"\x7fELF") {
+        return Err("not an ELF binary".into());
+    }
+    let is_64 = data.get(4) == Some(&2);
+    let little_endian = data.get(5) == Some(&1);
+    let read = |offset: usize, len: usize| -> Result<u64, Box<dyn Error>> {
+        let end = offset.checked_add(len).ok_or("malformed ELF header")?;
+        let bytes = data.get(offset..end).ok_or("truncated ELF header")?;
+        let mut value = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            let shift = if little_endian { i } else { len - 1 - i };
+            value |= u64::from(*byte) << (8 * shift);
+        }
+        Ok(value)
+    };
+
+    let (phoff, phentsize, phnum) = if is_64 {
+        (read(0x20, 8)?, read(0x36, 2)?, read(0x38, 2)?)
+    } else {
+        (read(0x1C, 4)?, read(0x2A, 2)?, read(0x2C, 2)?)
+    };
+    for index in 0..phnum {
+        let offset = index
+            .checked_mul(phentsize)
+            .and_then(|entry| entry.checked_add(phoff))
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or("malformed ELF program header offset")?;
+        let p_type = read(offset, 4)?;
+        if p_type == u64::from(PT_INTERP) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn check_executable_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(args.first().ok_or("File not set")?);
+    let metadata =
+        std::fs::metadata(path).map_err(|e| format!("{} is missing: {}", path.display(), e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", path.display()).into());
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err(format!(
+            "{} is not executable (mode {:o})",
+            path.display(),
+            metadata.permissions().mode() & 0o777
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// `chmod +x`, restoring executable bits that archive extraction dropped
+async fn make_executable_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(args.first().ok_or("File not set")?);
+    let mut permissions = std::fs::metadata(path)
+        .map_err(|e| format!("{} is missing: {}", path.display(), e))?
+        .permissions();
+    // grant execute wherever read is granted, like chmod +x under umask 022
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    std::fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+async fn normalize_eol_builtin(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    if args.is_empty() {
+        return Err("No files to normalize".into());
+    }
+    for file in &args {
+        normalize_eol(Path::new(file))?;
+    }
+    Ok(())
+}
+
+/// Convert CRLF line endings of `file` to LF in place
+fn normalize_eol(file: &Path) -> Result<(), Box<dyn Error>> {
+    let content = read_to_string(file)?;
+    if content.contains("\r\n") {
+        std::fs::write(file, content.replace("\r\n", "\n"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(limit: u64) -> &'static DiskQuota {
+        Box::leak(Box::new(DiskQuota::new(limit)))
+    }
+
+    fn used(quota: &DiskQuota) -> (u64, usize) {
+        let state = quota.state.lock().unwrap();
+        (state.used, state.in_flight)
+    }
+
+    #[tokio::test]
+    async fn quota_fails_mid_stream_and_returns_the_charge_on_drop() {
+        let quota = quota(100);
+        let mut charge = QuotaCharge::start_on(Some(quota)).await.unwrap();
+        let mut reader = QuotaReader {
+            inner: std::io::Cursor::new(vec![0; 150]),
+            charge: &mut charge,
+        };
+        let mut written = Vec::new();
+        let error = std::io::copy(&mut reader, &mut written).unwrap_err();
+        assert!(error.get_ref().is_some_and(|e| e.is::<QuotaExceeded>()));
+        assert!(written.len() <= 100);
+        assert!(charge.exceeded().is_some());
+        assert_eq!(used(quota), (written.len() as u64, 1));
+
+        drop(charge);
+        assert_eq!(used(quota), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn quota_release_returns_part_of_the_charge() {
+        let quota = quota(100);
+        let mut charge = QuotaCharge::start_on(Some(quota)).await.unwrap();
+        charge.add(60).unwrap();
+        charge.release(20);
+        assert_eq!(used(quota), (40, 1));
+        assert!(charge.add(70).is_err());
+        charge.add(60).unwrap();
+        assert_eq!(used(quota), (100, 1));
+    }
+
+    #[tokio::test]
+    async fn quota_holds_extracted_dirs_until_they_are_released() {
+        let quota = quota(50);
+        let dir = std::env::temp_dir().join("canvasbot-quota-test/extracted");
+        let mut charge = QuotaCharge::start_on(Some(quota)).await.unwrap();
+        charge.add(50).unwrap();
+        charge.hold(&dir);
+        assert_eq!(used(quota), (50, 0));
+
+        // nothing running could make room, so waiting would never end
+        assert!(QuotaCharge::start_on(Some(quota)).await.is_err());
+
+        quota.release_dir(dir.parent().unwrap());
+        assert_eq!(used(quota), (0, 0));
+        assert!(QuotaCharge::start_on(Some(quota)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn quota_start_waits_for_a_running_charge() {
+        let quota = quota(10);
+        let mut running = QuotaCharge::start_on(Some(quota)).await.unwrap();
+        running.add(10).unwrap();
+        let waiting = tokio::spawn(QuotaCharge::start_on(Some(quota)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(running);
+        let started = tokio::time::timeout(Duration::from_secs(5), waiting).await;
+        assert!(started.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn without_a_quota_anything_goes() {
+        let mut charge = QuotaCharge::start_on(None).await.unwrap();
+        charge.add(u64::MAX).unwrap();
+        assert!(charge.exceeded().is_none());
+    }
+
+    #[test]
+    fn lexer_skips_comments() {
+        let source = "// system()\n/* fork()\n exec() */ puts(x);";
+        assert_eq!(cpp_identifiers(source), [(3, "puts"), (3, "x")]);
+    }
+
+    #[test]
+    fn lexer_skips_string_and_char_literals() {
+        let source = r#"printf("system(\"ls\")"); char c = '\''; fork();"#;
+        assert_eq!(
+            cpp_identifiers(source),
+            [(1, "printf"), (1, "char"), (1, "c"), (1, "fork")]
+        );
+    }
+
+    #[test]
+    fn lexer_counts_lines_continued_in_strings() {
+        let source = "\"a\\\nb\";\nsystem();";
+        assert_eq!(cpp_identifiers(source), [(3, "system")]);
+    }
+
+    #[test]
+    fn lexer_skips_raw_strings() {
+        let source = "auto s = R\"(system(\"ls\"))\"; fork();";
+        assert_eq!(
+            cpp_identifiers(source),
+            [(1, "auto"), (1, "s"), (1, "fork")]
+        );
+    }
+
+    #[test]
+    fn lexer_skips_raw_strings_up_to_their_delimiter() {
+        let source = "u8R\"x(a)\" system()\n)x\" exec();";
+        assert_eq!(cpp_identifiers(source), [(2, "exec")]);
+    }
+
+    #[test]
+    fn lexer_reads_prefixed_literals_and_numbers() {
+        let source = "L\"system\" 0x1f 1e5 1'000 fork";
+        assert_eq!(cpp_identifiers(source), [(1, "L"), (1, "fork")]);
+    }
+
+    /// 64-bit little-endian ELF with one program header per `p_type`
+    fn elf64(p_types: &[u32]) -> Vec<u8> {
+        let mut elf = vec![0; 64];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3A].copy_from_slice(&(p_types.len() as u16).to_le_bytes());
+        for p_type in p_types {
+            let mut header = vec![0; 56];
+            header[..4].copy_from_slice(&p_type.to_le_bytes());
+            elf.extend(header);
+        }
+        elf
+    }
+
+    #[test]
+    fn elf_interpreter_marks_dynamic_binaries() {
+        assert!(elf_has_interpreter(&elf64(&[6, 3, 1])).unwrap());
+        assert!(!elf_has_interpreter(&elf64(&[6, 1, 1])).unwrap());
+        assert!(!elf_has_interpreter(&elf64(&[])).unwrap());
+    }
+
+    #[test]
+    fn elf_interpreter_reads_32_bit_big_endian_headers() {
+        let mut elf = vec![0; 52];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x02");
+        elf[0x1C..0x20].copy_from_slice(&52u32.to_be_bytes());
+        elf[0x2A..0x2C].copy_from_slice(&32u16.to_be_bytes());
+        elf[0x2C..0x2E].copy_from_slice(&1u16.to_be_bytes());
+        let mut header = vec![0; 32];
+        header[..4].copy_from_slice(&3u32.to_be_bytes());
+        elf.extend(header);
+        assert!(elf_has_interpreter(&elf).unwrap());
+    }
+
+    #[test]
+    fn elf_interpreter_rejects_malformed_binaries() {
+        assert!(elf_has_interpreter(b"#!/bin/sh\n").is_err());
+        assert!(elf_has_interpreter(b"\x7fELF\x02\x01").is_err());
+        // program headers past the end of the file
+        let mut truncated = elf64(&[3]);
+        truncated.truncate(66);
+        assert!(elf_has_interpreter(&truncated).is_err());
+        // offsets that overflow must fail instead of panicking
+        let mut overflowing = elf64(&[1, 1]);
+        overflowing[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(elf_has_interpreter(&overflowing).is_err());
+    }
 }
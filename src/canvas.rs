@@ -1,9 +1,23 @@
-use crate::config::Config;
+use crate::builtin::QuotaCharge;
+use crate::config::{AttemptPolicy, Config};
+use chrono::{DateTime, FixedOffset, Local};
+use futures::StreamExt;
+use log::warn;
 use reqwest::header::HeaderMap;
+use reqwest::multipart::{Form, Part};
 use reqwest::Client;
 use reqwest::Response;
+use reqwest::StatusCode;
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Attachment {
@@ -11,7 +25,7 @@ pub struct Attachment {
     uuid: String,
     folder_id: u64,
     display_name: String,
-    filename: String,
+    pub filename: String,
     upload_status: String,
     #[serde(rename = "content-type")]
     content_type: String,
@@ -22,7 +36,7 @@ pub struct Attachment {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct Submission {
+pub struct Submission {
     #[serde(skip_serializing_if = "Option::is_none")]
     assignment_id: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -30,13 +44,13 @@ pub(crate) struct Submission {
     #[serde(skip_serializing_if = "Option::is_none")]
     course: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    attempt: Option<u32>,
+    pub attempt: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    body: Option<String>,
+    pub body: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     grade: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    grade_matches_current_submission: Option<bool>,
+    pub grade_matches_current_submission: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     html_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -46,16 +60,16 @@ pub(crate) struct Submission {
     #[serde(skip_serializing_if = "Option::is_none")]
     submission_comments: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    submission_type: Option<String>,
+    pub submission_type: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    submitted_at: Option<String>,
+    pub submitted_at: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub url: Option<String>,
 
     pub user_id: u32,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    grader_id: Option<i32>,
+    pub grader_id: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     graded_at: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -90,6 +104,134 @@ pub(crate) struct Submission {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub attachments: Option<Vec<Attachment>>,
+    /// Group of a group assignment, only fetched with `group_grading`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<SubmissionGroup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubmissionGroup {
+    pub id: Option<u64>,
+    pub name: Option<String>,
+}
+
+impl Submission {
+    /// Id of the group the submission belongs to, if fetched
+    pub fn group_id(&self) -> Option<u64> {
+        self.group.as_ref().and_then(|group| group.id)
+    }
+
+    /// URL submitted as an `online_url`, or the first link in a text entry
+    pub fn linked_url(&self) -> Option<String> {
+        match self.submission_type.as_deref() {
+            Some("online_url") => self.url.clone(),
+            Some("online_text_entry") => {
+                let body = self.body.as_deref()?;
+                let start = body.find("https://").or_else(|| body.find("http://"))?;
+                let url: String = body[start..]
+                    .chars()
+                    .take_while(|c| !c.is_whitespace() && !matches!(c, '"' | '\'' | '<' | '>'))
+                    .collect();
+                Some(url)
+            }
+            _ => None,
+        }
+    }
+
+    /// URL and file name of the submitted work, or why there is none. Text
+    /// and URL entries are graded from the link they contain.
+    pub fn source(&self) -> Result<(String, String), &'static str> {
+        match self.submission_type.as_deref() {
+            Some("online_url") | Some("online_text_entry") => self
+                .linked_url()
+                .map(|url| (url, String::new()))
+                .ok_or("No submission URL found"),
+            _ => self
+                .attachments
+                .as_ref()
+                .and_then(|attachments| attachments.first())
+                .map(|attachment| (attachment.url.clone(), attachment.filename.clone()))
+                .ok_or("No attachments found"),
+        }
+    }
+
+    /// Score to post for this attempt under `policy`, keeping the current
+    /// score of an earlier attempt when it is higher and `policy` is `Best`
+    pub fn kept_score(&self, score: u32, policy: AttemptPolicy) -> u32 {
+        match (policy, self.score) {
+            (AttemptPolicy::Best, Some(previous)) if previous.round() as u32 > score => {
+                previous.round() as u32
+            }
+            _ => score,
+        }
+    }
+
+    /// Whether the submission was submitted after `time`
+    pub fn submitted_after(&self, time: DateTime<FixedOffset>) -> bool {
+        self.submitted_at
+            .as_deref()
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+            .is_some_and(|at| at > time)
+    }
+
+    /// Whether the submission is in one of `skip_states` with an up-to-date grade
+    pub fn is_done(&self, skip_states: &[String]) -> bool {
+        skip_states.contains(&self.workflow_state)
+            && self.grade_matches_current_submission != Some(false)
+    }
+
+    /// Whether the current grade was given by someone other than `autograder_id`
+    pub fn is_manually_graded(&self, autograder_id: i64) -> bool {
+        match self.grader_id {
+            Some(grader_id) => {
+                i64::from(grader_id) != autograder_id
+                    && self.grade_matches_current_submission != Some(false)
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Assignment {
+    pub id: u32,
+    pub name: String,
+    /// Points the assignment is worth, `None` when it is ungraded
+    pub points_possible: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadTarget {
+    upload_url: String,
+    upload_params: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadedFile {
+    id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentedSubmission {
+    #[serde(default)]
+    submission_comments: Vec<SubmissionComment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubmissionComment {
+    id: u64,
+    comment: String,
+}
+
+struct SubmissionPage {
+    submissions: Vec<Submission>,
+    next: Option<String>,
+    last: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -108,30 +250,232 @@ pub struct Comment {
     text_comment: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RubricUpdate {
+    rubric_assessment: HashMap<String, CriterionPoints>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CriterionPoints {
+    points: f64,
+}
+
 pub struct Canvas {
     pub client: Arc<Client>,
     pub config: Arc<Config>,
     pub url: String,
     pub header: String,
+    requests: AtomicUsize,
+    self_id: OnceCell<i64>,
+    /// The graded assignment and when it was fetched
+    assignment: Mutex<Option<(Instant, Arc<Assignment>)>>,
+    manual_posting: OnceCell<()>,
+    /// When each user's grade was last posted
+    posted: Mutex<HashMap<u32, Instant>>,
+    /// Attempt each user's progress comment was posted on, and its id
+    progress: Mutex<HashMap<u32, (Option<u32>, u64)>>,
 }
 
 impl Canvas {
     const AUTHORIZATION_HEADER: &'static str = "Authorization";
+    /// Attempts made to read a submission page before failing the fetch
+    const PAGE_ATTEMPTS: usize = 3;
+    /// Numbered submission pages fetched at once
+    const PAGE_CONCURRENCY: usize = 4;
+    /// Attempts made to post a score that conflicts with another update
+    const UPDATE_ATTEMPTS: usize = 3;
+    /// Ends a comment cut down to `max_comment_bytes`
+    const TRUNCATED_MARKER: &'static str = "\n…(truncated)";
+    /// How long the fetched assignment is reused, so that edits to its
+    /// points possible are picked up by a running daemon
+    const ASSIGNMENT_TTL: Duration = Duration::from_secs(600);
 
     pub fn new(client: Arc<Client>, config: Arc<Config>) -> Self {
-        let url = format!(
-            "{}/api/v1/courses/{}/assignments/{}/submissions",
-            config.api_url, config.sep_course_id, config.lab_assignment_id
-        );
+        let url = match config.section_id {
+            Some(section_id) => format!(
+                "{}/api/v1/sections/{}/assignments/{}/submissions",
+                config.api_url, section_id, config.lab_assignment_id
+            ),
+            None => format!(
+                "{}/api/v1/courses/{}/assignments/{}/submissions",
+                config.api_url, config.sep_course_id, config.lab_assignment_id
+            ),
+        };
         let header = format!("Bearer {}", config.api_key);
         Self {
             client,
             config,
             url,
             header,
+            requests: AtomicUsize::new(0),
+            self_id: OnceCell::new(),
+            assignment: Mutex::new(None),
+            manual_posting: OnceCell::new(),
+            posted: Mutex::new(HashMap::new()),
+            progress: Mutex::new(HashMap::new()),
         }
     }
 
+    /// User id owning the API token, i.e. the autograder itself
+    pub async fn get_self_id(&self) -> Result<i64, Box<dyn std::error::Error>> {
+        let id = self
+            .self_id
+            .get_or_try_init(|| async {
+                self.requests.fetch_add(1, Ordering::Relaxed);
+                let user: User = self
+                    .client
+                    .get(format!("{}/api/v1/users/self", self.config.api_url))
+                    .header(Self::AUTHORIZATION_HEADER, &self.header)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                Ok::<_, reqwest::Error>(user.id)
+            })
+            .await?;
+        Ok(*id)
+    }
+
+    /// Copy the submitted attachment into `archive_dir` as
+    /// `<user_id>-<attempt>-<filename>`. An existing copy is never replaced.
+    pub async fn archive_submission(
+        &self,
+        submission: &Submission,
+    ) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        let Some(archive_dir) = &self.config.archive_dir else {
+            return Ok(None);
+        };
+        let (url, filename) = submission.source()?;
+        let filename = if filename.is_empty() {
+            "submission".to_string()
+        } else {
+            filename
+        };
+        let attempt = submission
+            .attempt
+            .map(|a| a.to_string())
+            .unwrap_or_else(|| "0".to_string());
+        let path =
+            Path::new(archive_dir).join(format!("{}-{}-{}", submission.user_id, attempt, filename));
+        if path.exists() {
+            return Ok(Some(path));
+        }
+
+        std::fs::create_dir_all(archive_dir)?;
+        // write under a temporary name so a crash never leaves a partial copy,
+        // charging the disk quota while the copy streams in
+        let partial = path.with_extension("part");
+        let streamed: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+            let mut charge = QuotaCharge::start().await?;
+            let mut response = self.client.get(&url).send().await?.error_for_status()?;
+            let mut file = File::create(&partial)?;
+            while let Some(chunk) = response.chunk().await? {
+                charge.add(chunk.len() as u64)?;
+                file.write_all(&chunk)?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = streamed {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        let mut permissions = std::fs::metadata(&partial)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&partial, permissions)?;
+        std::fs::rename(&partial, &path)?;
+        Ok(Some(path))
+    }
+
+    /// The graded assignment, fetched again once the cached one is older
+    /// than `ASSIGNMENT_TTL`
+    pub async fn get_assignment(&self) -> Result<Arc<Assignment>, Box<dyn std::error::Error>> {
+        if let Some(assignment) = self.cached_assignment() {
+            return Ok(assignment);
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let assignment: Arc<Assignment> = self
+            .client
+            .get(format!(
+                "{}/api/v1/courses/{}/assignments/{}",
+                self.config.api_url, self.config.sep_course_id, self.config.lab_assignment_id
+            ))
+            .header(Self::AUTHORIZATION_HEADER, &self.header)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Assignment>()
+            .await?
+            .into();
+        *self.assignment.lock().unwrap() = Some((Instant::now(), assignment.clone()));
+        Ok(assignment)
+    }
+
+    fn cached_assignment(&self) -> Option<Arc<Assignment>> {
+        let cached = self.assignment.lock().unwrap();
+        cached
+            .as_ref()
+            .filter(|(fetched, _)| fetched.elapsed() < Self::ASSIGNMENT_TTL)
+            .map(|(_, assignment)| assignment.clone())
+    }
+
+    /// Set the assignment's post policy to manual, once per process. Canvas
+    /// only exposes post policies through GraphQL.
+    async fn ensure_manual_posting(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.manual_posting
+            .get_or_try_init(|| async {
+                let query = format!(
+                    "mutation {{ setAssignmentPostPolicy(input: {{assignmentId: \"{}\", postManually: true}}) {{ errors {{ message }} }} }}",
+                    self.config.lab_assignment_id
+                );
+                self.requests.fetch_add(1, Ordering::Relaxed);
+                let response: serde_json::Value = self
+                    .client
+                    .post(format!("{}/api/graphql", self.config.api_url))
+                    .header(Self::AUTHORIZATION_HEADER, &self.header)
+                    .json(&serde_json::json!({ "query": query }))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let errors = &response["data"]["setAssignmentPostPolicy"]["errors"];
+                if let Some(error) = response["errors"].get(0).or_else(|| errors.get(0)) {
+                    return Err(format!("Failed to set manual post policy: {}", error["message"]).into());
+                }
+                Ok::<_, Box<dyn std::error::Error>>(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Whether `user_id` had a grade posted less than `regrade_cooldown_secs` ago
+    pub fn in_regrade_cooldown(&self, user_id: u32) -> bool {
+        let cooldown = Duration::from_secs(self.config.regrade_cooldown_secs);
+        let mut posted = self.posted.lock().unwrap();
+        posted.retain(|_, at| at.elapsed() < cooldown);
+        posted.contains_key(&user_id)
+    }
+
+    /// Id of the progress comment posted on `attempt` of `user_id`, if any
+    pub fn progress_comment(&self, user_id: u32, attempt: Option<u32>) -> Option<u64> {
+        match self.progress.lock().unwrap().get(&user_id) {
+            Some(&(posted_on, id)) if posted_on == attempt => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Remember the progress comment posted on `attempt` of `user_id`
+    pub fn set_progress_comment(&self, user_id: u32, attempt: Option<u32>, id: u64) {
+        self.progress.lock().unwrap().insert(user_id, (attempt, id));
+    }
+
+    /// Number of Canvas requests sent since the last call, resetting the count
+    pub fn take_request_count(&self) -> usize {
+        self.requests.swap(0, Ordering::Relaxed)
+    }
+
     pub async fn get_all_sub<F>(
         &self,
         filter_fn: F,
@@ -139,35 +483,182 @@ impl Canvas {
     where
         F: Fn(&Submission) -> bool,
     {
-        let mut submissions: Vec<Submission> = Vec::new();
-        let mut next_url = Some(self.url.clone());
+        let mut first_url = format!("{}?per_page=100", self.url);
+        for include in self.submission_includes() {
+            first_url.push_str(&format!("&include[]={}", include));
+        }
+        let first = self.get_sub_page(&first_url).await?;
+        let mut submissions: Vec<Submission> =
+            first.submissions.into_iter().filter(&filter_fn).collect();
 
+        // with a numbered last page the rest can be fetched concurrently,
+        // otherwise follow the next links one page at a time
+        if let Some(urls) = first.last.as_deref().and_then(Self::numbered_pages) {
+            let pages: Vec<_> = futures::stream::iter(urls)
+                .map(|url| async move { self.get_sub_page(&url).await.map_err(|e| e.to_string()) })
+                .buffered(Self::PAGE_CONCURRENCY)
+                .collect()
+                .await;
+            for page in pages {
+                submissions.extend(page?.submissions.into_iter().filter(&filter_fn));
+            }
+            return Ok(submissions);
+        }
+
+        let mut next_url = first.next;
         while let Some(url) = next_url {
+            let page = self.get_sub_page(&url).await?;
+            next_url = page.next;
+            submissions.extend(page.submissions.into_iter().filter(&filter_fn));
+        }
+        Ok(submissions)
+    }
+
+    /// Fetch the submission of a single user without paging the roster
+    pub async fn get_submission_by_user(
+        &self,
+        user_id: u32,
+    ) -> Result<Submission, Box<dyn std::error::Error>> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let submission = self
+            .client
+            .get(format!("{}/{}", self.url, user_id))
+            .query(
+                &self
+                    .submission_includes()
+                    .iter()
+                    .map(|include| ("include[]", include))
+                    .collect::<Vec<_>>(),
+            )
+            .header(Self::AUTHORIZATION_HEADER, &self.header)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(submission)
+    }
+
+    /// Associations requested with every submission, so a single one carries
+    /// the same fields as a page of them
+    fn submission_includes(&self) -> &'static [&'static str] {
+        if self.config.group_grading {
+            &["group"]
+        } else {
+            &[]
+        }
+    }
+
+    /// User ids sharing the grade of `submission`: every member of its group
+    /// with `group_grading`, otherwise only the submitter
+    pub async fn graded_users(
+        &self,
+        submission: &Submission,
+    ) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+        let (true, Some(group_id)) = (self.config.group_grading, submission.group_id()) else {
+            return Ok(vec![submission.user_id]);
+        };
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let members: Vec<User> = self
+            .client
+            .get(format!(
+                "{}/api/v1/groups/{}/users?per_page=100",
+                self.config.api_url, group_id
+            ))
+            .header(Self::AUTHORIZATION_HEADER, &self.header)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let mut user_ids = vec![submission.user_id];
+        for member in members {
+            let id = u32::try_from(member.id)?;
+            if !user_ids.contains(&id) {
+                user_ids.push(id);
+            }
+        }
+        Ok(user_ids)
+    }
+
+    /// Fetch one page of submissions and the URL of the next page, retrying
+    /// pages whose body fails to deserialize
+    async fn get_sub_page(&self, url: &str) -> Result<SubmissionPage, Box<dyn std::error::Error>> {
+        let mut attempt = 1;
+        loop {
+            self.requests.fetch_add(1, Ordering::Relaxed);
             let response = self
                 .client
-                .get(&url)
+                .get(url)
                 .header(Self::AUTHORIZATION_HEADER, &self.header)
                 .send()
                 .await?;
 
-            // resolve next page URL first
-            next_url = Self::get_next_link(response.headers(), &self.header);
+            // resolve next and last page URLs first
+            let next = Self::get_link(response.headers(), "next", &self.header);
+            let last = Self::get_link(response.headers(), "last", &self.header);
 
             // current page submissions
-            let page_submissions: Vec<Submission> = response.json().await?;
-            submissions.extend(page_submissions.into_iter().filter(&filter_fn));
+            match response.json().await {
+                Ok(submissions) => {
+                    return Ok(SubmissionPage {
+                        submissions,
+                        next,
+                        last,
+                    })
+                }
+                Err(e) if attempt < Self::PAGE_ATTEMPTS => {
+                    warn!("Failed to read submission page {}, retrying: {}", url, e);
+                    attempt += 1;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Err(e) => {
+                    return Err(format!("Failed to read submission page {}: {}", url, e).into())
+                }
+            }
         }
-        Ok(submissions)
     }
 
-    /// Get next page URL from Link header
-    fn get_next_link(headers: &HeaderMap, access_token: &str) -> Option<String> {
+    /// URLs of pages 2 up to `last`, if it names its page by number rather
+    /// than by an opaque bookmark
+    fn numbered_pages(last: &str) -> Option<Vec<String>> {
+        let last = Url::parse(last).ok()?;
+        let count: u32 = last
+            .query_pairs()
+            .find(|(key, _)| key == "page")?
+            .1
+            .parse()
+            .ok()?;
+        let urls = (2..=count)
+            .map(|page| {
+                let pairs: Vec<(String, String)> = last
+                    .query_pairs()
+                    .map(|(key, value)| {
+                        let value = if key == "page" {
+                            page.to_string()
+                        } else {
+                            value.into_owned()
+                        };
+                        (key.into_owned(), value)
+                    })
+                    .collect();
+                let mut url = last.clone();
+                url.query_pairs_mut().clear().extend_pairs(pairs);
+                url.to_string()
+            })
+            .collect();
+        Some(urls)
+    }
+
+    /// Get the page URL of relation `rel` from Link header
+    fn get_link(headers: &HeaderMap, rel: &str, access_token: &str) -> Option<String> {
+        let rel = format!("rel=\"{}\"", rel);
         if let Some(link_header) = headers.get("Link") {
             let link_str = link_header.to_str().ok()?;
 
             // find "rel=\"next\"" part in Link header
             for link in link_str.split(',') {
-                if link.contains("rel=\"next\"") {
+                if link.contains(&rel) {
                     let parts: Vec<&str> = link.split(';').collect();
                     if let Some(url_part) = parts.first() {
                         // re-append access token to the next URL
@@ -189,29 +680,291 @@ impl Canvas {
         None
     }
 
+    /// Comment as posted to Canvas, with the configured header, history line
+    /// and footer around it
+    fn format_comment(&self, attempt: Option<u32>, comment: &str) -> String {
+        let mut parts = Vec::new();
+        if let Some(header) = &self.config.comment_header {
+            parts.push(header.clone());
+        }
+        if self.config.comment_history {
+            let attempt = attempt.map_or("?".to_string(), |a| a.to_string());
+            parts.push(format!(
+                "Attempt {} graded at {}",
+                attempt,
+                Local::now().format("%Y-%m-%d %H:%M:%S")
+            ));
+        }
+        let index = parts.len();
+        parts.push(comment.to_string());
+        if let Some(footer) = &self.config.comment_footer {
+            parts.push(footer.clone());
+        }
+
+        // cut the comment itself so the header and footer survive
+        if let Some(max) = self.config.max_comment_bytes {
+            let len: usize = parts.iter().map(|part| part.len() + 1).sum::<usize>() - 1;
+            if len > max {
+                let keep = comment
+                    .len()
+                    .saturating_sub(len - max + Self::TRUNCATED_MARKER.len());
+                let end = (0..=keep)
+                    .rev()
+                    .find(|&i| comment.is_char_boundary(i))
+                    .unwrap_or(0);
+                parts[index] = format!("{}{}", &comment[..end], Self::TRUNCATED_MARKER);
+            }
+        }
+        parts.join("\n")
+    }
+
     pub async fn update_score(
         &self,
         sub_id: u32,
+        attempt: Option<u32>,
         score: u32,
         comment: &str,
     ) -> Result<Response, Box<dyn std::error::Error>> {
+        if self.config.post_manually {
+            self.ensure_manual_posting().await?;
+        }
+        // clamp to what the assignment is currently worth on Canvas
+        let points_possible = match self.get_assignment().await {
+            Ok(assignment) => assignment.points_possible,
+            Err(e) => {
+                warn!(
+                    "Failed to get the points possible, posting score {} of user {} unclamped: {}",
+                    score, sub_id, e
+                );
+                None
+            }
+        };
+        let score = match points_possible {
+            Some(points) if f64::from(score) > points => {
+                warn!(
+                    "Clamping score {} of user {} to the {} points possible",
+                    score, sub_id, points
+                );
+                points as u32
+            }
+            _ => score,
+        };
         let url = format!("{}/{}", self.url, sub_id);
         let score_update = ScoreUpdate {
             submission: SubmissionScore {
                 posted_grade: score,
             },
             comment: Comment {
-                text_comment: comment.to_string(),
+                text_comment: self.format_comment(attempt, comment),
             },
         };
 
+        let mut attempt = 1;
+        loop {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            let response = self
+                .client
+                .put(&url)
+                .header(Self::AUTHORIZATION_HEADER, &self.header)
+                .json(&score_update)
+                .send()
+                .await?;
+            let status = response.status();
+            if status.is_success() {
+                self.posted.lock().unwrap().insert(sub_id, Instant::now());
+                return Ok(response);
+            }
+
+            // conflicts come from racing updates of the same submission
+            let conflict = matches!(
+                status,
+                StatusCode::CONFLICT | StatusCode::UNPROCESSABLE_ENTITY
+            );
+            if conflict && attempt < Self::UPDATE_ATTEMPTS {
+                warn!("Updating score of user {} got {}, retrying", sub_id, status);
+                tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                attempt += 1;
+                continue;
+            }
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Canvas rejected the score of user {}: {} {}",
+                sub_id, status, body
+            )
+            .into());
+        }
+    }
+
+    /// Post points per rubric criterion, keyed by the criterion id
+    pub async fn update_rubric_assessment(
+        &self,
+        sub_id: u32,
+        criteria_scores: &[(String, f64)],
+    ) -> Result<Response, Box<dyn std::error::Error>> {
+        if self.config.post_manually {
+            self.ensure_manual_posting().await?;
+        }
+        let url = format!("{}/{}", self.url, sub_id);
+        let rubric_update = RubricUpdate {
+            rubric_assessment: criteria_scores
+                .iter()
+                .map(|(criterion, points)| (criterion.clone(), CriterionPoints { points: *points }))
+                .collect(),
+        };
+
+        self.requests.fetch_add(1, Ordering::Relaxed);
         let response = self
             .client
             .put(&url)
             .header(Self::AUTHORIZATION_HEADER, &self.header)
-            .json(&score_update)
+            .json(&rubric_update)
             .send()
             .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Canvas rejected the rubric assessment of user {}: {} {}",
+                sub_id, status, body
+            )
+            .into());
+        }
         Ok(response)
     }
+
+    /// Comment on the submission of `sub_id` without touching its grade.
+    /// return: id of the new comment, if Canvas reported it
+    pub async fn post_comment(
+        &self,
+        sub_id: u32,
+        text: &str,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        if self.config.post_manually {
+            self.ensure_manual_posting().await?;
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let submission: CommentedSubmission = self
+            .client
+            .put(format!(
+                "{}/{}?include[]=submission_comments",
+                self.url, sub_id
+            ))
+            .header(Self::AUTHORIZATION_HEADER, &self.header)
+            .json(&serde_json::json!({ "comment": { "text_comment": text } }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(submission
+            .submission_comments
+            .iter()
+            .rev()
+            .find(|comment| comment.comment == text)
+            .map(|comment| comment.id))
+    }
+
+    /// Replace the text of comment `comment_id` on the submission of `sub_id`
+    pub async fn edit_comment(
+        &self,
+        sub_id: u32,
+        comment_id: u64,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.client
+            .put(format!("{}/{}/comments/{}", self.url, sub_id, comment_id))
+            .header(Self::AUTHORIZATION_HEADER, &self.header)
+            .form(&[("comment", text)])
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Post the same score and comment for each of `user_ids`, trying every
+    /// user before reporting the failures
+    pub async fn update_scores(
+        &self,
+        user_ids: &[u32],
+        attempt: Option<u32>,
+        score: u32,
+        comment: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut errors = Vec::new();
+        for &user_id in user_ids {
+            if let Err(e) = self.update_score(user_id, attempt, score, comment).await {
+                errors.push(e.to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; ").into())
+        }
+    }
+
+    /// Attach a file to the submission as a comment, following Canvas'
+    /// upload flow: request an upload slot, upload the file, then comment
+    pub async fn upload_comment_file(
+        &self,
+        sub_id: u32,
+        path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("Attachment path has no file name")?
+            .to_string();
+        let content = std::fs::read(path)?;
+
+        // 1. request an upload slot for the file
+        let url = format!(
+            "{}/api/v1/courses/{}/assignments/{}/submissions/{}/comments/files",
+            self.config.api_url, self.config.sep_course_id, self.config.lab_assignment_id, sub_id
+        );
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let target: UploadTarget = self
+            .client
+            .post(&url)
+            .header(Self::AUTHORIZATION_HEADER, &self.header)
+            .form(&[("name", name.clone()), ("size", content.len().to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // 2. upload the file along with the parameters Canvas handed out
+        let mut form = Form::new();
+        for (key, value) in target.upload_params {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                value => value.to_string(),
+            };
+            form = form.text(key, value);
+        }
+        form = form.part("file", Part::bytes(content).file_name(name));
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let file: UploadedFile = self
+            .client
+            .post(&target.upload_url)
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // 3. attach the uploaded file to a new submission comment
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.client
+            .put(format!("{}/{}", self.url, sub_id))
+            .header(Self::AUTHORIZATION_HEADER, &self.header)
+            .json(&serde_json::json!({ "comment": { "file_ids": [file.id] } }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 }
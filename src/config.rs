@@ -1,5 +1,8 @@
 use serde::Deserialize;
 use serde::Serialize;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
@@ -10,16 +13,387 @@ pub struct Config {
     pub sep_course_id: u32,
     pub lab_assignment_id: u32,
     pub docker_image: String,
+    /// Container command, with `{user_id}`, `{attempt}`, `{attachment_url}`
+    /// and `{attachment_filename}` substituted per submission. The same values
+    /// are set as `USER_ID`, `ATTEMPT`, ... in the container's environment.
+    /// Exit codes 126 and 127 mark the image as misconfigured unless the
+    /// container printed `container::PIPELINE_STARTED` first.
     pub docker_cmd: Vec<String>,
+    /// Pull `docker_image` when the daemon starts, so a rebuilt image is
+    /// picked up without pulling it by hand
+    #[serde(default)]
+    pub pull_image: bool,
+    /// Login to the registry of `docker_image`, looked up in Docker's own
+    /// config file and credential helpers when unset
+    #[serde(default)]
+    pub registry_auth: Option<RegistryAuth>,
     pub lab_timeout: u64,
     #[serde(default = "default_fetch_filter")]
     pub fetch_filter: Vec<String>,
+    /// States counted as done while the grade matches the current submission
+    #[serde(default = "default_skip_states")]
+    pub skip_states: Vec<String>,
+    /// Prefix of container names, derived from `lab_name` when unset
+    #[serde(default)]
+    pub container_prefix: Option<String>,
+    /// Base directory holding one working directory per graded submission
+    #[serde(default = "default_workspace_dir")]
+    pub workspace_dir: String,
+    /// Only grade submissions of this course section
+    #[serde(default)]
+    pub section_id: Option<u32>,
+    /// Skip submissions whose current grade was given by someone else
+    #[serde(default = "default_protect_manual_grades")]
+    pub protect_manual_grades: bool,
+    /// User (name or UID[:GID]) running the grading container
+    #[serde(default)]
+    pub container_user: Option<String>,
+    /// Mount the container's root filesystem read-only
+    #[serde(default)]
+    pub container_readonly_rootfs: bool,
+    /// Path of a size-limited tmpfs mounted as scratch space
+    #[serde(default)]
+    pub container_tmpfs: Option<String>,
+    /// Size limit of `container_tmpfs` in bytes
+    #[serde(default = "default_container_tmpfs_size")]
+    pub container_tmpfs_size: u64,
+    /// Prefix every comment with its attempt number and grading time
+    #[serde(default)]
+    pub comment_history: bool,
+    /// Text placed before every posted comment
+    #[serde(default)]
+    pub comment_header: Option<String>,
+    /// Text placed after every posted comment
+    #[serde(default)]
+    pub comment_footer: Option<String>,
+    /// Append each step's captured output to the pipeline comment
+    #[serde(default)]
+    pub verbose_comment: bool,
+    /// Comment posted when the daemon starts grading a submission, edited
+    /// into a short status once grading ends
+    #[serde(default)]
+    pub progress_comment: Option<String>,
+    /// Text appended to the pipeline comment when every step passed
+    #[serde(default)]
+    pub success_comment: Option<String>,
+    /// Text appended to the pipeline comment when some step did not pass
+    #[serde(default)]
+    pub partial_comment: Option<String>,
+    /// Truncate posted comments to this many bytes, Canvas rejects huge ones
+    #[serde(default)]
+    pub max_comment_bytes: Option<usize>,
+    /// Switch the assignment to a manual posting policy before posting, so
+    /// grades and comments stay hidden until the instructor posts them
+    #[serde(default)]
+    pub post_manually: bool,
+    /// Seconds to skip a user after posting their grade, while Canvas
+    /// catches up on the new workflow state
+    #[serde(default)]
+    pub regrade_cooldown_secs: u64,
+    /// Post the grade of a group submission to every member of the group
+    #[serde(default)]
+    pub group_grading: bool,
+    /// JSON lines file recording submissions our infrastructure failed to
+    /// grade, regraded by the `retry` command
+    #[serde(default = "default_dead_letter_file")]
+    pub dead_letter_file: String,
+    /// Which attempt's score a student keeps when they submit several times
+    #[serde(default)]
+    pub attempt_policy: AttemptPolicy,
+    /// Directory keeping a read-only copy of every graded attachment, named
+    /// by user id and attempt, for academic-integrity audits
+    #[serde(default)]
+    pub archive_dir: Option<String>,
+    /// Delay each daemon cycle by a random 0 to this many seconds, so cycles
+    /// are 120s ± this much apart and instances on one host desynchronize
+    #[serde(default)]
+    pub interval_jitter_secs: u64,
+    /// Fetch the next daemon cycle's submissions while the current cycle is
+    /// still grading, instead of after it
+    #[serde(default)]
+    pub overlap_fetch: bool,
+    /// Bytes that downloads and extracted files may use at once, unbounded
+    /// when unset. Extracted files count until their directory is removed.
+    #[serde(default)]
+    pub max_workspace_bytes: Option<u64>,
+    /// Grade at most this many submissions at once, unbounded when unset
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    /// Ramp the concurrency up exponentially from 1 to `max_concurrent` over
+    /// this many completed gradings, smoothing the burst after a deadline
+    #[serde(default)]
+    pub concurrency_ramp: usize,
+    /// Post a 0 when our infrastructure fails to grade a submission, instead
+    /// of leaving the grade untouched and only dead-lettering it
+    #[serde(default)]
+    pub post_zero_on_infra_error: bool,
+    /// Score posted when the grading container crashes or exits non-zero
+    #[serde(default)]
+    pub crash_score: u32,
+    /// Post the container's exit code (0-125) as the score. Codes from 126
+    /// up are reserved by the shell and signals and still count as crashes.
+    #[serde(default)]
+    pub exit_code_score: bool,
+    /// Post the `rubric` points that `execute --json` prints in the grading
+    /// container as the rubric assessment of the graded users
+    #[serde(default)]
+    pub post_rubric: bool,
+    /// JSON file whose keys override this config, e.g. to keep `api_key` out
+    /// of version control
+    #[serde(default)]
+    pub secrets_file: Option<String>,
+    /// Image running custom pipeline commands with `execute --in-container`,
+    /// `docker_image` when unset
+    #[serde(default)]
+    pub pipeline_image: Option<String>,
+    /// Executables that pipeline `custom` commands and builtins may run, any
+    /// when unset
+    #[serde(default)]
+    pub allowed_commands: Option<Vec<String>>,
+    /// User-Agent sent with every Canvas request
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    /// Contact address sent as the `From` header of Canvas requests
+    #[serde(default)]
+    pub contact: Option<String>,
+    /// Budget in seconds for the tests themselves, passed to the container as
+    /// `TEST_TIMEOUT`. It must stay below `lab_timeout`, which remains the hard
+    /// limit covering container startup, the tests and teardown.
+    #[serde(default)]
+    pub test_timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttemptPolicy {
+    /// Post the score of the latest attempt
+    #[default]
+    Latest,
+    /// Never post a score below the one an earlier attempt already has
+    Best,
+}
+
+/// Username and password (or access token) of a private registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl Config {
+    pub fn container_prefix(&self) -> String {
+        match &self.container_prefix {
+            Some(prefix) => prefix.clone(),
+            None => self
+                .lab_name
+                .to_lowercase()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect(),
+        }
+    }
+
+    /// Pretty JSON of the effective config with the API key and registry
+    /// password redacted
+    pub fn to_redacted_json(&self) -> Result<String, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        value["api_key"] = serde_json::Value::String("<redacted>".to_string());
+        if self.registry_auth.is_some() {
+            value["registry_auth"]["password"] =
+                serde_json::Value::String("<redacted>".to_string());
+        }
+        serde_json::to_string_pretty(&value)
+    }
 }
 
 fn default_api_url() -> String {
     "https://oc.sjtu.edu.cn".to_string()
 }
 
+fn default_user_agent() -> String {
+    format!("canvasbot/{}", env!("CARGO_PKG_VERSION"))
+}
+
+fn default_protect_manual_grades() -> bool {
+    true
+}
+
+fn default_container_tmpfs_size() -> u64 {
+    64 * 1024 * 1024 // 64MB
+}
+
+fn default_dead_letter_file() -> String {
+    "dead_letter.jsonl".to_string()
+}
+
+fn default_workspace_dir() -> String {
+    "workspace".to_string()
+}
+
+fn default_skip_states() -> Vec<String> {
+    vec!["graded".to_string()]
+}
+
 fn default_fetch_filter() -> Vec<String> {
     vec!["submitted".to_string()]
 }
+
+fn read_json(path: &Path) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+pub fn load_config(config_path: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut contents = read_json(Path::new(config_path))?;
+
+    // overlay the secrets file, resolved relative to the config file
+    if let Some(secrets_file) = contents.get("secrets_file").and_then(|v| v.as_str()) {
+        let secrets_path = Path::new(config_path)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(secrets_file);
+        let secrets = read_json(&secrets_path)
+            .map_err(|e| format!("Failed to read {}: {}", secrets_path.display(), e))?;
+        let (Some(contents), serde_json::Value::Object(secrets)) =
+            (contents.as_object_mut(), secrets)
+        else {
+            return Err("config and secrets file must be JSON objects".into());
+        };
+        contents.extend(secrets);
+    }
+
+    expand_env(&mut contents)?;
+    let config: Config = serde_json::from_value(contents)?;
+    validate_config(&config)?;
+    Ok(config)
+}
+
+/// Replace `${VAR}` in every string of `value` with the environment variable
+fn expand_env(value: &mut serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+    match value {
+        serde_json::Value::String(s) => *s = expand_env_str(s)?,
+        serde_json::Value::Array(values) => {
+            for value in values {
+                expand_env(value)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for value in map.values_mut() {
+                expand_env(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_env_str(s: &str) -> Result<String, Box<dyn std::error::Error>> {
+    expand_vars(s, |name| std::env::var(name).ok())
+}
+
+/// Replace `${VAR}` in `s` with what `lookup` finds for `VAR`, failing on
+/// unknown variables and on a `${` that is never closed
+fn expand_vars(
+    s: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err("Unterminated ${ in config value".into());
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let value = lookup(name)
+            .ok_or_else(|| format!("Environment variable {} used in config is not set", name))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+pub fn validate_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if config.lab_name.is_empty() {
+        return Err("LAB_NAME is empty in config.json".into());
+    }
+    if config.api_key.is_empty() {
+        return Err("API_KEY is empty in config.json".into());
+    }
+    if config.api_url.is_empty() {
+        return Err("API_URL is empty in config.json".into());
+    }
+    if config.sep_course_id == 0 {
+        return Err("SEP_COURSE_ID is not set in config.json".into());
+    }
+    if config.lab_assignment_id == 0 {
+        return Err("LAB3_ASSIGNMENT_ID is not set in config.json".into());
+    }
+    if config.docker_image.is_empty() {
+        return Err("DOCKER_IMAGE is not set in config.json".into());
+    }
+    if config.docker_cmd.is_empty() {
+        return Err("DOCKER_CMD is not set in config.json".into());
+    }
+    if config.lab_timeout == 0 {
+        return Err("LAB_TIMEOUT is not set or is zero in config.json".into());
+    }
+    if config
+        .test_timeout
+        .is_some_and(|test_timeout| test_timeout >= config.lab_timeout)
+    {
+        return Err("TEST_TIMEOUT must be less than LAB_TIMEOUT in config.json".into());
+    }
+    if config.max_concurrent == Some(0) {
+        return Err("MAX_CONCURRENT must be at least 1 in config.json".into());
+    }
+    if config.concurrency_ramp > 0 && config.max_concurrent.is_none() {
+        return Err("CONCURRENCY_RAMP needs MAX_CONCURRENT in config.json".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "TAG" => Some("v2".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expands_variables() {
+        let expanded = expand_vars("image:${TAG}-${EMPTY}x ${TAG}", lookup).unwrap();
+        assert_eq!(expanded, "image:v2-x v2");
+    }
+
+    #[test]
+    fn leaves_text_without_variables_alone() {
+        assert_eq!(expand_vars("$TAG {TAG} $", lookup).unwrap(), "$TAG {TAG} $");
+    }
+
+    #[test]
+    fn values_are_not_expanded_again() {
+        let nested = |name: &str| (name == "A").then(|| "${B}".to_string());
+        assert_eq!(expand_vars("${A}", nested).unwrap(), "${B}");
+    }
+
+    #[test]
+    fn rejects_unset_variables() {
+        let error = expand_vars("${MISSING}", lookup).unwrap_err();
+        assert!(error.to_string().contains("MISSING"));
+    }
+
+    #[test]
+    fn rejects_unterminated_variables() {
+        assert!(expand_vars("image:${TAG", lookup).is_err());
+        assert!(expand_vars("${TAG}${", lookup).is_err());
+    }
+}
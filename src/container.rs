@@ -0,0 +1,1238 @@
+use crate::canvas::{Canvas, Submission};
+use crate::config::Config;
+use base64::Engine;
+use bollard::auth::DockerCredentials;
+use bollard::container::CreateContainerOptions;
+use bollard::container::ListContainersOptions;
+use bollard::container::LogOutput;
+use bollard::container::LogsOptions;
+use bollard::container::RemoveContainerOptions;
+use bollard::container::StartContainerOptions;
+use bollard::container::WaitContainerOptions;
+use bollard::image::CreateImageOptions;
+use bollard::service::ContainerWaitResponse;
+use bollard::Docker;
+use chrono::Utc;
+use futures::StreamExt;
+use indexmap::IndexMap;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, Duration};
+
+/// Substitute submission placeholders in `docker_cmd`. A command without any
+/// placeholder gets the user id appended, as before placeholders existed.
+fn container_cmd(
+    template: &[String],
+    placeholders: &[(&str, String)],
+    user_id: u32,
+) -> Vec<String> {
+    let templated = template
+        .iter()
+        .any(|arg| placeholders.iter().any(|(key, _)| arg.contains(key)));
+    let mut cmd: Vec<String> = template
+        .iter()
+        .map(|arg| {
+            placeholders
+                .iter()
+                .fold(arg.clone(), |arg, (key, value)| arg.replace(key, value))
+        })
+        .collect();
+    if !templated {
+        cmd.push(user_id.to_string());
+    }
+    cmd
+}
+
+/// Docker labels identifying the containers started by canvasbot
+pub const LAB_LABEL: &str = "canvasbot.lab";
+pub const USER_LABEL: &str = "canvasbot.user_id";
+
+/// How grading a single submission ended, for the cycle summary
+pub enum Outcome {
+    Finished,
+    NoAttachment,
+    Failed,
+    /// The grading image or `docker_cmd` is broken, nothing was posted
+    Misconfigured,
+}
+
+/// Submission our infrastructure failed to grade, kept for `retry`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub user_id: u32,
+    /// Attempt that failed, missing in letters written before it was kept
+    #[serde(default)]
+    pub attempt: Option<u32>,
+    pub reason: String,
+    pub timestamp: String,
+    /// A placeholder 0 was posted, so the submission looks graded until
+    /// it is retried
+    #[serde(default)]
+    pub zero_posted: bool,
+}
+
+/// Log an infrastructure failure and append it to `dead_letter_file`,
+/// unless the same attempt of the user is already there
+pub fn dead_letter(
+    config: &Config,
+    user_id: u32,
+    attempt: Option<u32>,
+    reason: String,
+    zero_posted: bool,
+) -> Outcome {
+    // serializes the check for an existing letter with the append
+    static WRITING: Mutex<()> = Mutex::new(());
+
+    error!("{}", reason);
+    let letter = DeadLetter {
+        user_id,
+        attempt,
+        reason,
+        timestamp: Utc::now().to_rfc3339(),
+        zero_posted,
+    };
+    let _writing = WRITING.lock().unwrap();
+    let listed = std::fs::read_to_string(&config.dead_letter_file)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str::<DeadLetter>(line).ok())
+        .any(|listed| listed.user_id == user_id && listed.attempt == attempt);
+    if listed {
+        info!(
+            "Attempt {:?} of user {} is already dead-lettered",
+            attempt, user_id
+        );
+        return Outcome::Failed;
+    }
+    let written = serde_json::to_string(&letter)
+        .map_err(std::io::Error::from)
+        .and_then(|line| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.dead_letter_file)?;
+            // one write per line keeps concurrent appends from interleaving
+            file.write_all(format!("{}\n", line).as_bytes())
+        });
+    if let Err(e) = written {
+        error!(
+            "Failed to write {} to {}: {}",
+            user_id, config.dead_letter_file, e
+        );
+    }
+    Outcome::Failed
+}
+
+/// Comment posted with the placeholder 0 of `post_zero_on_infra_error`
+const INFRA_ERROR_COMMENT: &str =
+    "The test environment failed, this submission will be graded again";
+
+/// Dead-letter a submission our infrastructure failed to grade, first
+/// posting a 0 to `graded_users` with `post_zero_on_infra_error`
+async fn infra_failure(
+    canvas: &Canvas,
+    submission: &Submission,
+    graded_users: &[u32],
+    reason: String,
+) -> Outcome {
+    let mut zero_posted = false;
+    if canvas.config.post_zero_on_infra_error {
+        let score = submission.kept_score(0, canvas.config.attempt_policy);
+        match canvas
+            .update_scores(graded_users, submission.attempt, score, INFRA_ERROR_COMMENT)
+            .await
+        {
+            Ok(()) => zero_posted = true,
+            Err(e) => error!("Error updating score: {:?}", e),
+        }
+    }
+    dead_letter(
+        &canvas.config,
+        submission.user_id,
+        submission.attempt,
+        reason,
+        zero_posted,
+    )
+}
+
+/// Read and clear the dead letters, one per user. Users failing again are
+/// written back while they are regraded.
+pub fn take_dead_letters(config: &Config) -> Result<Vec<DeadLetter>, Box<dyn std::error::Error>> {
+    let contents = match std::fs::read_to_string(&config.dead_letter_file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut letters: IndexMap<u32, DeadLetter> = IndexMap::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let letter: DeadLetter = serde_json::from_str(line)?;
+        letters.insert(letter.user_id, letter);
+    }
+    std::fs::remove_file(&config.dead_letter_file)?;
+    Ok(letters.into_values().collect())
+}
+
+/// Grade one submission in a fresh container of the configured image,
+/// keeping the `progress_comment` up to date. It is posted once per attempt,
+/// and only when `post_progress`; regrading an attempt reuses its comment.
+pub async fn grade_submission(
+    docker: Arc<Docker>,
+    canvas: Arc<Canvas>,
+    submission: Submission,
+    post_progress: bool,
+) -> Outcome {
+    let user_id = submission.user_id;
+    let attempt = submission.attempt;
+    let progress = match &canvas.config.progress_comment {
+        Some(text) => match canvas.progress_comment(user_id, attempt) {
+            Some(comment_id) => {
+                if let Err(e) = canvas.edit_comment(user_id, comment_id, text).await {
+                    warn!(
+                        "Failed to reset progress comment for user {}: {}",
+                        user_id, e
+                    );
+                }
+                Some(comment_id)
+            }
+            None if post_progress => match canvas.post_comment(user_id, text).await {
+                Ok(comment_id) => {
+                    if let Some(comment_id) = comment_id {
+                        canvas.set_progress_comment(user_id, attempt, comment_id);
+                    }
+                    comment_id
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to post progress comment for user {}: {}",
+                        user_id, e
+                    );
+                    None
+                }
+            },
+            None => None,
+        },
+        None => None,
+    };
+
+    let outcome = run_grading(docker, canvas.clone(), submission).await;
+
+    if let Some(comment_id) = progress {
+        let status = match outcome {
+            Outcome::Finished => "Grading finished.",
+            Outcome::NoAttachment => "Grading skipped, no attachment was found.",
+            Outcome::Failed => "Grading did not complete normally.",
+            Outcome::Misconfigured => "Grading could not run, it will be retried once fixed.",
+        };
+        if let Err(e) = canvas.edit_comment(user_id, comment_id, status).await {
+            warn!(
+                "Failed to update progress comment for user {}: {}",
+                user_id, e
+            );
+        }
+    }
+    outcome
+}
+
+async fn run_grading(docker: Arc<Docker>, canvas: Arc<Canvas>, submission: Submission) -> Outcome {
+    // one name per attempt, so a resubmission never meets the container
+    // of the previous attempt
+    let container_name = format!(
+        "{}-{}-a{}",
+        canvas.config.container_prefix(),
+        submission.user_id,
+        submission.attempt.unwrap_or(0)
+    );
+    let user_id = submission.user_id;
+    let attempt = submission.attempt;
+    let policy = canvas.config.attempt_policy;
+    let kept = |score| submission.kept_score(score, policy);
+    info!("Start testing for user ID: {}", user_id);
+    // errors are not `Send`, so they become strings before the next await
+    let graded_users = match canvas
+        .graded_users(&submission)
+        .await
+        .map_err(|e| e.to_string())
+    {
+        Ok(user_ids) => user_ids,
+        Err(e) => {
+            return infra_failure(
+                &canvas,
+                &submission,
+                &[user_id],
+                format!("Failed to get group members of user {}: {}", user_id, e),
+            )
+            .await;
+        }
+    };
+
+    let (source_url, source_filename) = match submission.source() {
+        Ok(source) => source,
+        Err(missing) => {
+            warn!("User ID {} submitted no attachment", user_id);
+            if let Err(e) = canvas
+                .update_scores(&graded_users, attempt, kept(0), missing)
+                .await
+            {
+                error!("Error updating score: {:?}", e);
+            }
+            return Outcome::NoAttachment;
+        }
+    };
+
+    // keep the graded attachment before grading it, or not at all
+    match canvas
+        .archive_submission(&submission)
+        .await
+        .map_err(|e| e.to_string())
+    {
+        Ok(Some(path)) => info!(
+            "Archived submission of user {} at {}",
+            user_id,
+            path.display()
+        ),
+        Ok(None) => {}
+        Err(e) => {
+            return infra_failure(
+                &canvas,
+                &submission,
+                &graded_users,
+                format!("Failed to archive submission of user {}: {}", user_id, e),
+            )
+            .await;
+        }
+    }
+
+    let placeholders = [
+        ("{user_id}", user_id.to_string()),
+        (
+            "{attempt}",
+            attempt.map(|a| a.to_string()).unwrap_or_default(),
+        ),
+        ("{attachment_url}", source_url),
+        ("{attachment_filename}", source_filename),
+    ];
+    let cmd = container_cmd(&canvas.config.docker_cmd, &placeholders, user_id);
+    // the placeholders double as environment variables, e.g. `$ATTACHMENT_URL`,
+    // which `sh -c` commands can quote safely unlike substituted text
+    let mut env: Vec<String> = placeholders
+        .iter()
+        .map(|(key, value)| format!("{}={}", key.trim_matches(['{', '}']).to_uppercase(), value))
+        .collect();
+    if let Some(secs) = canvas.config.test_timeout {
+        env.push(format!("TEST_TIMEOUT={}", secs));
+    }
+    let user_label = user_id.to_string();
+    let labels = HashMap::from([
+        (LAB_LABEL, canvas.config.lab_name.as_str()),
+        (USER_LABEL, user_label.as_str()),
+    ]);
+
+    // a container left over from an earlier run of this attempt would make
+    // the create below fail with a name conflict
+    let stale = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    match docker.remove_container(&container_name, Some(stale)).await {
+        Ok(()) => warn!("Removed stale container {}", container_name),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => {}
+        Err(e) => warn!("Failed to remove stale container {}: {}", container_name, e),
+    }
+
+    if let Err(e) = docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: &container_name,
+                platform: None,
+            }),
+            bollard::container::Config {
+                image: Some(canvas.config.docker_image.as_str()),
+                cmd: Some(cmd.iter().map(String::as_str).collect()),
+                user: canvas.config.container_user.as_deref(),
+                labels: Some(labels),
+                env: Some(env.iter().map(String::as_str).collect()),
+                host_config: Some(bollard::service::HostConfig {
+                    memory: Some(1_073_741_824), // 1GB
+                    readonly_rootfs: Some(canvas.config.container_readonly_rootfs),
+                    tmpfs: canvas.config.container_tmpfs.as_ref().map(|path| {
+                        HashMap::from([(
+                            path.clone(),
+                            format!("rw,size={}", canvas.config.container_tmpfs_size),
+                        )])
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        )
+        .await
+    {
+        // a missing image or rejected config is ours to fix, not the student's
+        if let bollard::errors::Error::DockerResponseServerError {
+            status_code: 400 | 404,
+            ..
+        } = e
+        {
+            error!(
+                "Grading image misconfigured, cannot create container for user {}: {}",
+                user_id, e
+            );
+            return Outcome::Misconfigured;
+        }
+        return infra_failure(
+            &canvas,
+            &submission,
+            &graded_users,
+            format!("Failed to create container {}: {}", container_name, e),
+        )
+        .await;
+    };
+
+    info!("Container {} created", container_name);
+
+    // Start the container
+    if let Err(e) = docker
+        .start_container(&container_name, None::<StartContainerOptions<String>>)
+        .await
+    {
+        let message = e.to_string();
+        if message.contains("executable file not found") || message.contains("no such file") {
+            error!(
+                "Grading image misconfigured, docker_cmd cannot run for user {}: {}",
+                user_id, message
+            );
+            return Outcome::Misconfigured;
+        }
+        return infra_failure(
+            &canvas,
+            &submission,
+            &graded_users,
+            format!("Failed to start container {}: {}", container_name, message),
+        )
+        .await;
+    }
+
+    // Wait for container
+    let wait_options = WaitContainerOptions {
+        condition: "not-running".to_string(),
+    };
+    let mut wait_stream = docker.wait_container::<String>(&container_name, Some(wait_options));
+    let waited = match timeout(
+        Duration::from_secs(canvas.config.lab_timeout),
+        wait_stream.next(),
+    )
+    .await
+    {
+        Ok(Some(Ok(ContainerWaitResponse {
+            status_code: code, ..
+        })))
+        | Ok(Some(Err(bollard::errors::Error::DockerContainerWaitError { code, .. }))) => Ok(code),
+        Ok(Some(Err(e))) => Err(format!(
+            "Error waiting for container {}: {}",
+            container_name, e
+        )),
+        Ok(None) => Err(format!(
+            "wait_container stream of {} ended unexpectedly",
+            container_name
+        )),
+        Err(_) => {
+            // Test timeout
+            error!("Container for user {} timed out", user_id);
+            if let Err(e) = docker.stop_container(&container_name, None).await {
+                error!("Error stopping container: {:?}", e);
+            }
+            remove_container(&docker, &container_name).await;
+            if let Err(e) = canvas
+                .update_scores(&graded_users, attempt, kept(0), "Test timeout")
+                .await
+            {
+                error!("Error updating score: {:?}", e);
+            }
+            return Outcome::Failed;
+        }
+    };
+    // the container stays until its whole output is read, which following
+    // it from before the start could miss
+    let logs = match waited {
+        Ok(code) if canvas.config.post_rubric || matches!(code, 126 | 127) => {
+            match read_logs(&docker, &container_name).await {
+                Ok((stdout, stderr)) => Some((
+                    String::from_utf8_lossy(&stdout).into_owned(),
+                    String::from_utf8_lossy(&stderr).into_owned(),
+                )),
+                Err(e) => {
+                    error!("Failed to read the output of {}: {}", container_name, e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    remove_container(&docker, &container_name).await;
+    let code = match waited {
+        Ok(code) => code,
+        Err(reason) => return infra_failure(&canvas, &submission, &graded_users, reason).await,
+    };
+    let started = logs.as_ref().is_some_and(|(stdout, stderr)| {
+        stdout
+            .lines()
+            .chain(stderr.lines())
+            .any(|line| line.trim_end() == PIPELINE_STARTED)
+    });
+
+    let outcome = match classify_exit(code, canvas.config.exit_code_score, started) {
+        ExitKind::Score(score) => {
+            info!("Container for user {} scored {}", user_id, code);
+            match canvas
+                .update_scores(
+                    &graded_users,
+                    attempt,
+                    kept(score),
+                    &format!("Score reported by the test environment: {}", code),
+                )
+                .await
+                .map_err(|e| e.to_string())
+            {
+                Ok(()) => Outcome::Finished,
+                Err(e) => {
+                    infra_failure(
+                        &canvas,
+                        &submission,
+                        &graded_users,
+                        format!("Error updating score of user {}: {}", user_id, e),
+                    )
+                    .await
+                }
+            }
+        }
+        ExitKind::Success => {
+            info!("Container for user {} finished successfully", user_id);
+            Outcome::Finished
+        }
+        ExitKind::Misconfigured => {
+            error!(
+                "Grading image misconfigured, docker_cmd exited with code {} for user {}",
+                code, user_id
+            );
+            Outcome::Misconfigured
+        }
+        ExitKind::Crashed => {
+            error!("Container for user {} exited with code {}", user_id, code);
+            // 137 is SIGKILL, which is what the OOM killer sends
+            let comment = if code == 137 {
+                "Test environment was killed, possibly for running out of memory".to_string()
+            } else {
+                format!("Test environment exited with code {}", code)
+            };
+            if let Err(e) = canvas
+                .update_scores(
+                    &graded_users,
+                    attempt,
+                    kept(canvas.config.crash_score),
+                    &comment,
+                )
+                .await
+            {
+                error!("Error updating score: {:?}", e);
+            }
+            Outcome::Failed
+        }
+    };
+
+    if let (Outcome::Finished, true, Some((stdout, _))) =
+        (&outcome, canvas.config.post_rubric, logs)
+    {
+        post_printed_rubric(&canvas, &graded_users, &stdout).await;
+    }
+
+    info!("Finish {}", submission.user_id);
+    outcome
+}
+
+/// Line `execute` prints once the pipeline is about to run. Other entry
+/// scripts can print it too once their own setup is done.
+pub const PIPELINE_STARTED: &str = "canvasbot: pipeline started";
+
+/// How the exit code of a grading container is handled
+#[derive(Debug, PartialEq, Eq)]
+enum ExitKind {
+    /// The code is the score, with `exit_code_score`
+    Score(u32),
+    Success,
+    /// `docker_cmd` itself could not run
+    Misconfigured,
+    Crashed,
+}
+
+/// Handling of the exit `code` of a grading container. The shell's "not
+/// executable" and "command not found" codes only blame `docker_cmd` when
+/// the container never printed `PIPELINE_STARTED`, later student code exits
+/// with them too.
+fn classify_exit(code: i64, exit_code_score: bool, started: bool) -> ExitKind {
+    match code {
+        0..=125 if exit_code_score => ExitKind::Score(code as u32),
+        0 => ExitKind::Success,
+        126 | 127 if !started => ExitKind::Misconfigured,
+        _ => ExitKind::Crashed,
+    }
+}
+
+/// Rubric points in the last top-level object of pretty-printed JSON in
+/// `stdout`, as `execute --json` prints them
+fn printed_rubric(stdout: &str) -> Option<Vec<(String, f64)>> {
+    #[derive(Deserialize)]
+    struct Printed {
+        rubric: IndexMap<String, f64>,
+    }
+    let start = match stdout.rfind("\n{\n") {
+        Some(index) => index + 1,
+        None if stdout.starts_with("{\n") => 0,
+        None => return None,
+    };
+    let printed: Printed = serde_json::Deserializer::from_str(&stdout[start..])
+        .into_iter()
+        .next()?
+        .ok()?;
+    Some(printed.rubric.into_iter().collect())
+}
+
+/// Post the rubric printed by the grading container to every graded user
+async fn post_printed_rubric(canvas: &Canvas, graded_users: &[u32], stdout: &str) {
+    let Some(rubric) = printed_rubric(stdout) else {
+        warn!(
+            "Grading container printed no rubric for users {:?}",
+            graded_users
+        );
+        return;
+    };
+    if rubric.is_empty() {
+        return;
+    }
+    for &user_id in graded_users {
+        if let Err(e) = canvas.update_rubric_assessment(user_id, &rubric).await {
+            error!(
+                "Error updating rubric assessment of user {}: {}",
+                user_id, e
+            );
+        }
+    }
+}
+
+/// Stdout and stderr of the exited container `name`
+async fn read_logs(
+    docker: &Docker,
+    name: &str,
+) -> Result<(Vec<u8>, Vec<u8>), bollard::errors::Error> {
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let mut logs = docker.logs(
+        name,
+        Some(LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            ..Default::default()
+        }),
+    );
+    while let Some(log) = logs.next().await {
+        match log? {
+            LogOutput::StdErr { message } => stderr.extend_from_slice(&message),
+            log => stdout.extend_from_slice(&log.into_bytes()),
+        }
+    }
+    Ok((stdout, stderr))
+}
+
+/// Force-remove the container `name`, which may already be gone
+async fn remove_container(docker: &Docker, name: &str) {
+    let options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    match docker.remove_container(name, Some(options)).await {
+        Ok(())
+        | Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => {}
+        Err(e) => error!("Error removing container {}: {:?}", name, e),
+    }
+}
+
+/// Runs pipeline commands in throwaway containers of a tooling image, with
+/// the workspace bind-mounted at the same path and no network access
+pub struct Sandbox {
+    docker: Docker,
+    image: String,
+    user: Option<String>,
+    lab_name: String,
+    user_id: String,
+    timeout: Duration,
+}
+
+/// Force-removes a sandbox container unless disarmed, so a step timeout
+/// dropping the command does not leave the container behind
+struct RemoveOnDrop {
+    docker: Docker,
+    name: Option<String>,
+}
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            let docker = self.docker.clone();
+            tokio::spawn(async move {
+                let _ = docker
+                    .remove_container(
+                        &name,
+                        Some(RemoveContainerOptions {
+                            force: true,
+                            ..Default::default()
+                        }),
+                    )
+                    .await;
+            });
+        }
+    }
+}
+
+impl Sandbox {
+    /// Sandbox for the pipeline of `user_id`, running `pipeline_image` and
+    /// labelled like the grading containers so `cleanup` finds leftovers
+    pub fn new(docker: Docker, config: &Config, user_id: String) -> Self {
+        Self {
+            docker,
+            image: config
+                .pipeline_image
+                .clone()
+                .unwrap_or_else(|| config.docker_image.clone()),
+            user: config.container_user.clone(),
+            lab_name: config.lab_name.clone(),
+            user_id,
+            timeout: Duration::from_secs(config.lab_timeout),
+        }
+    }
+
+    /// Run `action` with `args` in a fresh container and collect its output
+    /// like `tokio::process::Command::output`
+    pub async fn output(
+        &self,
+        action: &str,
+        args: &[String],
+        workspace: &Path,
+    ) -> Result<std::process::Output, Box<dyn std::error::Error>> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "canvasbot-step-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let workspace = workspace.to_string_lossy();
+        let cmd: Vec<&str> = std::iter::once(action)
+            .chain(args.iter().map(String::as_str))
+            .collect();
+        let env = format!("SEP_ROOT_DIR={}", workspace);
+        let labels = HashMap::from([
+            (LAB_LABEL, self.lab_name.as_str()),
+            (USER_LABEL, self.user_id.as_str()),
+        ]);
+
+        self.docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: name.as_str(),
+                    platform: None,
+                }),
+                bollard::container::Config {
+                    image: Some(self.image.as_str()),
+                    cmd: Some(cmd),
+                    user: self.user.as_deref(),
+                    labels: Some(labels),
+                    env: Some(vec![env.as_str()]),
+                    working_dir: Some(&workspace),
+                    network_disabled: Some(true),
+                    host_config: Some(bollard::service::HostConfig {
+                        binds: Some(vec![format!("{}:{}", workspace, workspace)]),
+                        memory: Some(1_073_741_824), // 1GB
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let mut guard = RemoveOnDrop {
+            docker: self.docker.clone(),
+            name: Some(name.clone()),
+        };
+        self.docker
+            .start_container(&name, None::<StartContainerOptions<String>>)
+            .await?;
+
+        let mut wait = self
+            .docker
+            .wait_container(&name, None::<WaitContainerOptions<String>>);
+        // the guard removes the container when it times out
+        let code = match timeout(self.timeout, wait.next()).await {
+            Ok(Some(Ok(response))) => response.status_code,
+            Ok(Some(Err(bollard::errors::Error::DockerContainerWaitError { code, .. }))) => code,
+            Ok(Some(Err(e))) => return Err(e.into()),
+            Ok(None) => return Err("wait_container stream ended unexpectedly".into()),
+            Err(_) => {
+                return Err(format!(
+                    "{} timed out after {} seconds",
+                    action,
+                    self.timeout.as_secs()
+                )
+                .into())
+            }
+        };
+
+        let (stdout, stderr) = read_logs(&self.docker, &name).await?;
+
+        guard.name = None;
+        self.docker
+            .remove_container(
+                &name,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await?;
+        Ok(std::process::Output {
+            status: std::process::ExitStatus::from_raw((code as i32 & 0xff) << 8),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Key of Docker Hub in Docker's config file
+const DOCKER_HUB: &str = "https://index.docker.io/v1/";
+
+/// Registry host of `image`, Docker Hub when its first path component is
+/// not a host name
+fn registry_of(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => DOCKER_HUB,
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, DockerConfigAuth>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+    #[serde(default)]
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DockerConfigAuth {
+    /// base64 of `username:password`
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    identitytoken: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Credentials of `registry` from the config, else the ones `docker login`
+/// stored in Docker's config file or credential helpers
+async fn registry_credentials(
+    config: &Config,
+    registry: &str,
+) -> Result<Option<DockerCredentials>, Box<dyn std::error::Error>> {
+    if let Some(auth) = &config.registry_auth {
+        return Ok(Some(DockerCredentials {
+            username: Some(auth.username.clone()),
+            password: Some(auth.password.clone()),
+            serveraddress: Some(registry.to_string()),
+            ..Default::default()
+        }));
+    }
+
+    let dir = match (std::env::var_os("DOCKER_CONFIG"), std::env::var_os("HOME")) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(home)) => Path::new(&home).join(".docker"),
+        (None, None) => return Ok(None),
+    };
+    let Ok(contents) = std::fs::read_to_string(dir.join("config.json")) else {
+        return Ok(None);
+    };
+    let docker_config: DockerConfigFile = serde_json::from_str(&contents)?;
+
+    let helper = docker_config
+        .cred_helpers
+        .get(registry)
+        .or(docker_config.creds_store.as_ref());
+    if let Some(helper) = helper {
+        return credential_helper(helper, registry).await.map(Some);
+    }
+
+    let entry = docker_config
+        .auths
+        .get(registry)
+        .or_else(|| docker_config.auths.get(&format!("https://{}", registry)));
+    let Some(entry) = entry else {
+        return Ok(None);
+    };
+    let mut credentials = DockerCredentials {
+        identitytoken: entry.identitytoken.clone(),
+        serveraddress: Some(registry.to_string()),
+        ..Default::default()
+    };
+    if let Some(auth) = &entry.auth {
+        let decoded = String::from_utf8(base64::engine::general_purpose::STANDARD.decode(auth)?)?;
+        let (username, password) = decoded
+            .split_once(':')
+            .ok_or("Malformed auth in Docker config")?;
+        credentials.username = Some(username.to_string());
+        credentials.password = Some(password.to_string());
+    }
+    Ok(Some(credentials))
+}
+
+/// Ask the credential helper `docker-credential-<helper>` for the login of
+/// `registry`
+async fn credential_helper(
+    helper: &str,
+    registry: &str,
+) -> Result<DockerCredentials, Box<dyn std::error::Error>> {
+    let program = format!("docker-credential-{}", helper);
+    let mut child = tokio::process::Command::new(&program)
+        .arg("get")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(registry.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stdout).trim()
+        )
+        .into());
+    }
+    let found: HelperCredentials = serde_json::from_slice(&output.stdout)?;
+    // helpers store identity tokens under this placeholder user name
+    let credentials = if found.username == "<token>" {
+        DockerCredentials {
+            identitytoken: Some(found.secret),
+            ..Default::default()
+        }
+    } else {
+        DockerCredentials {
+            username: Some(found.username),
+            password: Some(found.secret),
+            ..Default::default()
+        }
+    };
+    Ok(DockerCredentials {
+        serveraddress: Some(registry.to_string()),
+        ..credentials
+    })
+}
+
+/// Pull `docker_image`, logging in to its registry when credentials are found
+pub async fn pull_image(
+    docker: &Docker,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image = config.docker_image.as_str();
+    let registry = registry_of(image);
+    let credentials = match registry_credentials(config, registry).await {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            warn!(
+                "No credentials for {}, pulling anonymously: {}",
+                registry, e
+            );
+            None
+        }
+    };
+    // without a tag Docker would pull every tag of the repository
+    let name = image.rsplit('/').next().unwrap_or(image);
+    let tag = if name.contains([':', '@']) {
+        ""
+    } else {
+        "latest"
+    };
+    let options = CreateImageOptions {
+        from_image: image,
+        tag,
+        ..Default::default()
+    };
+    let mut progress = docker.create_image(Some(options), None, credentials);
+    while let Some(info) = progress.next().await {
+        info?;
+    }
+    info!("Pulled image {}", image);
+    Ok(())
+}
+
+/// Force-remove this lab's containers that are stopped or older than `max_age`
+/// seconds, returning how many were removed
+pub async fn cleanup_containers(
+    docker: &Docker,
+    config: &Config,
+    max_age: u64,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let filters = HashMap::from([(
+        "label".to_string(),
+        vec![format!("{}={}", LAB_LABEL, config.lab_name)],
+    )]);
+    let containers = docker
+        .list_containers(Some(ListContainersOptions {
+            all: true,
+            filters,
+            ..Default::default()
+        }))
+        .await?;
+
+    let now = Utc::now().timestamp();
+    let mut removed = 0;
+    for container in containers {
+        let Some(id) = container.id else {
+            continue;
+        };
+        let stopped = container.state.as_deref() != Some("running");
+        let expired = container
+            .created
+            .is_some_and(|created| now - created > max_age as i64);
+        if !stopped && !expired {
+            continue;
+        }
+
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        match docker.remove_container(&id, Some(options)).await {
+            Ok(()) => {
+                info!("Removed orphaned container {}", id);
+                removed += 1;
+            }
+            Err(e) => error!("Error removing container {}: {:?}", id, e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Concurrency allowed after `completed` gradings, growing exponentially
+/// from 1 to `max` over the first `ramp` completions
+fn ramp_limit(max: usize, ramp: usize, completed: usize) -> usize {
+    if completed >= ramp {
+        return max;
+    }
+    let limit = (max as f64).powf(completed as f64 / ramp as f64);
+    (limit as usize).clamp(1, max)
+}
+
+/// Keep one submission per user, the one of their latest attempt, so the
+/// graded attempt no longer depends on the order Canvas pages them in
+fn latest_attempts(submissions: Vec<Submission>) -> Vec<Submission> {
+    let mut latest: IndexMap<u32, Submission> = IndexMap::new();
+    for submission in submissions {
+        match latest.get(&submission.user_id) {
+            Some(kept) if kept.attempt >= submission.attempt => {}
+            _ => {
+                latest.insert(submission.user_id, submission);
+            }
+        }
+    }
+    latest.into_values().collect()
+}
+
+/// Keep one submission per group, the one of its latest attempt, since
+/// grading any member grades the whole group
+fn one_per_group(submissions: Vec<Submission>) -> Vec<Submission> {
+    let mut kept: Vec<Submission> = Vec::with_capacity(submissions.len());
+    let mut groups: HashMap<u64, usize> = HashMap::new();
+    for submission in submissions {
+        let Some(group_id) = submission.group_id() else {
+            kept.push(submission);
+            continue;
+        };
+        match groups.get(&group_id) {
+            Some(&index) if kept[index].attempt >= submission.attempt => {}
+            Some(&index) => kept[index] = submission,
+            None => {
+                groups.insert(group_id, kept.len());
+                kept.push(submission);
+            }
+        }
+    }
+    kept
+}
+
+/// Grade every pending submission once
+pub async fn runner(docker: Arc<Docker>, canvas: Arc<Canvas>) {
+    if let Some(submissions) = fetch_pending(&canvas).await {
+        grade_all(docker, canvas, submissions).await;
+    }
+}
+
+/// Whether the state of `sub` leaves it due for grading
+pub fn is_due(canvas: &Canvas, sub: &Submission) -> bool {
+    canvas.config.fetch_filter.contains(&sub.workflow_state)
+        && !sub.is_done(&canvas.config.skip_states)
+        && !canvas.in_regrade_cooldown(sub.user_id)
+}
+
+/// Leave out the submissions graded by someone else with
+/// `protect_manual_grades`
+pub async fn without_manual_grades(
+    canvas: &Canvas,
+    submissions: Vec<Submission>,
+) -> Result<Vec<Submission>, Box<dyn std::error::Error>> {
+    if !canvas.config.protect_manual_grades {
+        return Ok(submissions);
+    }
+    let autograder_id = canvas
+        .get_self_id()
+        .await
+        .map_err(|e| format!("Failed to get autograder user: {}", e))?;
+    Ok(submissions
+        .into_iter()
+        .filter(|sub| {
+            let manual = sub.is_manually_graded(autograder_id);
+            if manual {
+                info!("Skipping manually graded user ID: {}", sub.user_id);
+            }
+            !manual
+        })
+        .collect())
+}
+
+/// Submissions due for grading, `None` when they could not be fetched
+pub async fn fetch_pending(canvas: &Canvas) -> Option<Vec<Submission>> {
+    let submissions = match canvas.get_all_sub(|sub| is_due(canvas, sub)).await {
+        Ok(subs) => subs,
+        Err(e) => {
+            error!("Failed to get submissions: {}", e);
+            return None;
+        }
+    };
+
+    let mut submissions = latest_attempts(submissions);
+    if canvas.config.group_grading {
+        submissions = one_per_group(submissions);
+    }
+
+    match without_manual_grades(canvas, submissions).await {
+        Ok(submissions) => Some(submissions),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Grade `submissions` and log a summary of the cycle
+pub async fn grade_all(docker: Arc<Docker>, canvas: Arc<Canvas>, submissions: Vec<Submission>) {
+    let max_concurrent = canvas
+        .config
+        .max_concurrent
+        .unwrap_or(Semaphore::MAX_PERMITS);
+    let ramp = canvas.config.concurrency_ramp;
+    let semaphore = Arc::new(Semaphore::new(ramp_limit(max_concurrent, ramp, 0)));
+    let completed = Arc::new(AtomicUsize::new(0));
+    let mut handles = vec![];
+
+    for submission in submissions {
+        let docker = Arc::clone(&docker);
+        let canvas = Arc::clone(&canvas);
+        let semaphore = Arc::clone(&semaphore);
+        let completed = Arc::clone(&completed);
+        let handle = tokio::spawn(async move {
+            let _permit = semaphore
+                .acquire()
+                .await
+                .expect("grading semaphore is never closed");
+            let outcome = grade_submission(docker, canvas, submission, true).await;
+            // widen the limit by however much this completion ramps it up
+            let done = completed.fetch_add(1, Ordering::Relaxed);
+            let grow =
+                ramp_limit(max_concurrent, ramp, done + 1) - ramp_limit(max_concurrent, ramp, done);
+            semaphore.add_permits(grow);
+            outcome
+        });
+        handles.push(handle);
+    }
+
+    let (mut finished, mut no_attachment, mut failed, mut misconfigured) = (0, 0, 0, 0);
+    for handle in handles {
+        match handle.await {
+            Ok(Outcome::Finished) => finished += 1,
+            Ok(Outcome::NoAttachment) => no_attachment += 1,
+            Ok(Outcome::Failed) => failed += 1,
+            Ok(Outcome::Misconfigured) => misconfigured += 1,
+            Err(e) => {
+                eprintln!("Task failed: {:?}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    info!(
+        "Cycle finished: {} graded, {} without attachment, {} failed, {} misconfigured, {} Canvas API requests",
+        finished,
+        no_attachment,
+        failed,
+        misconfigured,
+        canvas.take_request_count()
+    );
+    if misconfigured > 0 {
+        error!(
+            "{} submissions hit a misconfigured grading image and were left ungraded",
+            misconfigured
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_map_to_outcomes() {
+        assert_eq!(classify_exit(0, false, false), ExitKind::Success);
+        assert_eq!(classify_exit(1, false, true), ExitKind::Crashed);
+        assert_eq!(classify_exit(137, false, true), ExitKind::Crashed);
+        assert_eq!(classify_exit(42, true, true), ExitKind::Score(42));
+        assert_eq!(classify_exit(0, true, false), ExitKind::Score(0));
+        assert_eq!(classify_exit(137, true, true), ExitKind::Crashed);
+    }
+
+    #[test]
+    fn command_not_found_blames_docker_cmd_only_before_the_pipeline() {
+        assert_eq!(classify_exit(127, false, false), ExitKind::Misconfigured);
+        assert_eq!(classify_exit(126, true, false), ExitKind::Misconfigured);
+        // a student's run.sh calling a missing binary
+        assert_eq!(classify_exit(127, false, true), ExitKind::Crashed);
+        assert_eq!(classify_exit(126, true, true), ExitKind::Crashed);
+    }
+}
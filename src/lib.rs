@@ -0,0 +1,5 @@
+pub mod builtin;
+pub mod canvas;
+pub mod config;
+pub mod container;
+pub mod worker;
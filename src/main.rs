@@ -1,204 +1,28 @@
-mod builtin;
-mod canvas;
-mod config;
-mod worker;
-
-use bollard::container::CreateContainerOptions;
-use bollard::container::StartContainerOptions;
-use bollard::container::WaitContainerOptions;
 use bollard::Docker;
-use canvas::Canvas;
-use canvas::Submission;
-use clap::Parser;
-use config::Config;
-use futures::StreamExt;
+use canvasbot::builtin;
+use canvasbot::canvas::Canvas;
+use canvasbot::config::{load_config, Config};
+use canvasbot::container::{
+    cleanup_containers, dead_letter, fetch_pending, grade_all, grade_submission, is_due,
+    pull_image, runner, take_dead_letters, without_manual_grades, Outcome, Sandbox,
+    PIPELINE_STARTED,
+};
+use canvasbot::worker;
+use chrono::DateTime;
+use clap::{CommandFactory, Parser};
 use log::LevelFilter;
 use log::{error, info};
+use reqwest::header::{HeaderMap, HeaderValue, FROM};
 use reqwest::Client;
 use simple_logger::SimpleLogger;
-use std::fs::File;
-use std::io::Read;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::time::{interval, timeout, Duration};
+use tokio::time::{interval, Duration, MissedTickBehavior};
 use toml::Value;
 
-async fn start_container_runner(docker: Arc<Docker>, canvas: Arc<Canvas>, submission: Submission) {
-    let container_name = format!("lab3-{}", submission.user_id);
-    let user_id = submission.user_id;
-    info!("Start testing for user ID: {}", user_id);
-
-    let attachments = match submission.attachments {
-        Some(attachments) => attachments,
-        None => {
-            let _ = canvas
-                .update_score(user_id, 0, "No attachments found")
-                .await;
-            return;
-        }
-    };
-
-    let _attachment_url = match attachments.first() {
-        Some(attachment) => &attachment.url,
-        None => {
-            let _ = canvas
-                .update_score(user_id, 0, "No attachment URL found")
-                .await;
-            return;
-        }
-    };
-
-    if docker
-        .create_container(
-            Some(CreateContainerOptions {
-                name: &container_name,
-                platform: None,
-            }),
-            bollard::container::Config {
-                image: Some(canvas.config.docker_image.as_str()),
-                cmd: Some(
-                    canvas
-                        .config
-                        .docker_cmd
-                        .iter()
-                        .chain([&user_id.to_string()])
-                        .map(String::as_str)
-                        .collect(),
-                ),
-                host_config: Some(bollard::service::HostConfig {
-                    memory: Some(1_073_741_824), // 1GB
-                    auto_remove: Some(true),
-                    ..Default::default()
-                }),
-                ..Default::default()
-            },
-        )
-        .await
-        .is_err()
-    {
-        let _ = canvas
-            .update_score(user_id, 0, "Test environment startup error")
-            .await;
-    };
-
-    info!("Container {} created", container_name);
-
-    // Start the container
-    if (docker
-        .start_container(&container_name, None::<StartContainerOptions<String>>)
-        .await)
-        .is_err()
-    {
-        let _ = canvas
-            .update_score(user_id, 0, "Failed to start container")
-            .await;
-        return;
-    }
-
-    // Wait for container
-    let wait_options = WaitContainerOptions {
-        condition: "not-running".to_string(),
-    };
-    let mut wait_stream = docker.wait_container::<String>(&container_name, Some(wait_options));
-    match timeout(
-        Duration::from_secs(canvas.config.lab_timeout),
-        wait_stream.next(),
-    )
-    .await
-    {
-        Ok(Some(Ok(_))) => {
-            info!("Container for user {} finished successfully", user_id);
-        }
-        Ok(Some(Err(e))) => {
-            error!("Error waiting for container: {:?}", e);
-        }
-        Ok(None) => {
-            error!("wait_container stream ended unexpectedly");
-        }
-        Err(_) => {
-            // Test timeout
-            error!("Container for user {} timed out", user_id);
-            if let Err(e) = docker.stop_container(&container_name, None).await {
-                error!("Error stopping container: {:?}", e);
-            }
-            if let Err(e) = docker.remove_container(&container_name, None).await {
-                error!("Error removing container: {:?}", e);
-            }
-            if let Err(e) = canvas.update_score(user_id, 0, "Test timeout").await {
-                error!("Error updating score: {:?}", e);
-            }
-        }
-    }
-
-    info!("Finish {}", submission.user_id);
-}
-
-async fn runner(docker: Arc<Docker>, canvas: Arc<Canvas>) {
-    let submissions = match canvas
-        .get_all_sub(|sub| canvas.config.fetch_filter.contains(&sub.workflow_state))
-        .await
-    {
-        Ok(subs) => subs,
-        Err(e) => {
-            error!("Failed to get submissions: {}", e);
-            return;
-        }
-    };
-
-    let mut handles = vec![];
-
-    for submission in submissions {
-        let docker = Arc::clone(&docker);
-        let canvas = Arc::clone(&canvas);
-        let handle = tokio::spawn(async move {
-            start_container_runner(docker, canvas, submission).await;
-        });
-        handles.push(handle);
-    }
-
-    for handle in handles {
-        handle
-            .await
-            .unwrap_or_else(|e| eprintln!("Task failed: {:?}", e));
-    }
-}
-
-fn load_config(config_path: &str) -> Result<Config, Box<dyn std::error::Error>> {
-    let mut file = File::open(config_path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let config: Config = serde_json::from_str(&contents)?;
-    validate_config(&config)?;
-    Ok(config)
-}
-
-fn validate_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    if config.lab_name.is_empty() {
-        return Err("LAB_NAME is empty in config.json".into());
-    }
-    if config.api_key.is_empty() {
-        return Err("API_KEY is empty in config.json".into());
-    }
-    if config.api_url.is_empty() {
-        return Err("API_URL is empty in config.json".into());
-    }
-    if config.sep_course_id == 0 {
-        return Err("SEP_COURSE_ID is not set in config.json".into());
-    }
-    if config.lab_assignment_id == 0 {
-        return Err("LAB3_ASSIGNMENT_ID is not set in config.json".into());
-    }
-    if config.docker_image.is_empty() {
-        return Err("DOCKER_IMAGE is not set in config.json".into());
-    }
-    if config.docker_cmd.is_empty() {
-        return Err("DOCKER_CMD is not set in config.json".into());
-    }
-    if config.lab_timeout == 0 {
-        return Err("LAB_TIMEOUT is not set or is zero in config.json".into());
-    }
-    Ok(())
-}
-
 #[derive(Parser, Debug)]
 #[command(
     name = "canvasbot",
@@ -210,6 +34,12 @@ fn validate_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(
+        long,
+        global = true,
+        help = "Print the effective configuration and exit, with secrets redacted"
+    )]
+    print_config: bool,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -223,6 +53,43 @@ enum Commands {
         )]
         config: String,
     },
+    Cleanup {
+        #[arg(
+            short = 'f',
+            long,
+            default_value = "config.json",
+            help = "Path to the configuration file"
+        )]
+        config: String,
+        #[arg(
+            long,
+            help = "Remove running containers older than this many seconds [default: LAB_TIMEOUT]"
+        )]
+        max_age: Option<u64>,
+    },
+    Retry {
+        #[arg(
+            short = 'f',
+            long,
+            default_value = "config.json",
+            help = "Path to the configuration file"
+        )]
+        config: String,
+    },
+    List {
+        #[arg(
+            short = 'f',
+            long,
+            default_value = "config.json",
+            help = "Path to the configuration file"
+        )]
+        config: String,
+        #[arg(
+            long,
+            help = "Only list submissions submitted after this RFC 3339 time"
+        )]
+        since: Option<String>,
+    },
     Execute {
         #[arg(
             short = 'f',
@@ -238,13 +105,63 @@ enum Commands {
             help = "Path to the pipeline configuration file"
         )]
         pipeline: String,
-        #[arg(short, long, help = "Submission id of the test")]
-        sub_id: String,
-        #[arg(short, long, help = "URL of the attachment")]
-        url: String,
+        #[arg(
+            short,
+            long,
+            help = "Submission id of the test, required unless --print-config is given"
+        )]
+        sub_id: Option<String>,
+        #[arg(
+            short,
+            long,
+            help = "URL of the attachment [default: fetched from Canvas by sub_id]"
+        )]
+        url: Option<String>,
+        #[arg(long, help = "Keep working directories after the pipeline finishes")]
+        keep_workdir: bool,
+        #[arg(long, help = "Print the resolved pipeline without running it")]
+        plan: bool,
+        #[arg(long, help = "File uploaded to the submission as a comment attachment")]
+        attach: Option<String>,
+        #[arg(
+            long,
+            help = "Run custom commands in throwaway containers of PIPELINE_IMAGE, from the workspace. Builtins that run programs are refused"
+        )]
+        in_container: bool,
+        #[arg(long, help = "Write the comment to this file")]
+        output: Option<String>,
+        #[arg(
+            long,
+            help = "Write the score, comment and step results as JSON instead"
+        )]
+        json: bool,
     },
 }
 
+/// Random delay of up to `max_secs`, so that instances sharing a host drift
+/// apart instead of polling Canvas at the same moment
+fn jitter(max_secs: u64) -> Duration {
+    if max_secs == 0 {
+        return Duration::ZERO;
+    }
+    // a randomly seeded hasher is random enough without pulling in `rand`
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % (max_secs * 1000 + 1))
+}
+
+/// HTTP client identifying canvasbot to the Canvas admins
+fn build_client(config: &Config) -> Result<Client, Box<dyn std::error::Error>> {
+    let mut headers = HeaderMap::new();
+    if let Some(contact) = &config.contact {
+        headers.insert(FROM, HeaderValue::from_str(contact)?);
+    }
+    let client = Client::builder()
+        .user_agent(config.user_agent.as_str())
+        .default_headers(headers)
+        .build()?;
+    Ok(client)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     SimpleLogger::new()
@@ -253,13 +170,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .without_timestamps()
         .init()
         .unwrap();
-    console_subscriber::init();
+    // tokio-console binds a port and needs a `--cfg tokio_unstable` build
+    if std::env::var("CANVASBOT_TOKIO_CONSOLE").is_ok_and(|v| v == "1") {
+        console_subscriber::init();
+    }
     let cli = Cli::parse();
-    let client = Client::new();
+
+    if cli.print_config {
+        let (Commands::Daemon { config }
+        | Commands::Cleanup { config, .. }
+        | Commands::Retry { config }
+        | Commands::List { config, .. }
+        | Commands::Execute { config, .. }) = &cli.command;
+        println!("{}", load_config(config)?.to_redacted_json()?);
+        return Ok(());
+    }
 
     match cli.command {
         Commands::Daemon { config } => {
             let config = load_config(&config)?;
+            if let Some(bytes) = config.max_workspace_bytes {
+                builtin::set_disk_quota(bytes);
+            }
+            let client = build_client(&config)?;
             let canvas = Arc::new(Canvas::new(Arc::new(client), Arc::new(config)));
             let docker = Arc::new(
                 Docker::connect_with_local_defaults().expect("Failed to connect to Docker"),
@@ -267,61 +200,326 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             info!("{} Lab Runner Started", canvas.config.lab_name);
 
+            if canvas.config.pull_image {
+                // a failed pull still leaves any cached copy of the image
+                if let Err(e) = pull_image(&docker, &canvas.config).await {
+                    error!("Failed to pull {}: {}", canvas.config.docker_image, e);
+                }
+            }
+
+            if let Err(e) =
+                cleanup_containers(&docker, &canvas.config, canvas.config.lab_timeout).await
+            {
+                error!("Failed to clean up orphaned containers: {}", e);
+            }
+
             // Run every 2 minutes
             let mut interval = interval(Duration::from_secs(120));
+            // after a slow cycle, wait for the next slot instead of catching up
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            if !canvas.config.overlap_fetch {
+                loop {
+                    runner(docker.clone(), canvas.clone()).await;
+                    interval.tick().await;
+                    tokio::time::sleep(jitter(canvas.config.interval_jitter_secs)).await;
+                }
+            }
+            // prefetch one cycle ahead, leaving out the users and groups
+            // still being graded since their new grades are not on Canvas yet
+            let mut batch = fetch_pending(&canvas).await.unwrap_or_default();
             loop {
-                runner(docker.clone(), canvas.clone()).await;
-                interval.tick().await;
+                let grading: HashSet<u32> = batch.iter().map(|sub| sub.user_id).collect();
+                let grading_groups: HashSet<u64> =
+                    batch.iter().filter_map(|sub| sub.group_id()).collect();
+                let prefetch = async {
+                    interval.tick().await;
+                    tokio::time::sleep(jitter(canvas.config.interval_jitter_secs)).await;
+                    fetch_pending(&canvas).await
+                };
+                let ((), next) =
+                    tokio::join!(grade_all(docker.clone(), canvas.clone(), batch), prefetch);
+                batch = next
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|sub| {
+                        !grading.contains(&sub.user_id)
+                            && sub
+                                .group_id()
+                                .is_none_or(|id| !grading_groups.contains(&id))
+                    })
+                    .collect();
+            }
+        }
+        Commands::Cleanup { config, max_age } => {
+            let config = load_config(&config)?;
+            let docker = Docker::connect_with_local_defaults()?;
+            let max_age = max_age.unwrap_or(config.lab_timeout);
+            let removed = cleanup_containers(&docker, &config, max_age).await?;
+            info!("Removed {} orphaned containers", removed);
+        }
+        Commands::Retry { config } => {
+            let config = load_config(&config)?;
+            if let Some(bytes) = config.max_workspace_bytes {
+                builtin::set_disk_quota(bytes);
+            }
+            let client = build_client(&config)?;
+            let canvas = Arc::new(Canvas::new(Arc::new(client), Arc::new(config)));
+            let docker = Arc::new(Docker::connect_with_local_defaults()?);
+            let letters = take_dead_letters(&canvas.config)?;
+            info!("Retrying {} dead-lettered submissions", letters.len());
+            let mut finished = 0;
+            for letter in &letters {
+                info!("Retrying user {} after: {}", letter.user_id, letter.reason);
+                let submission = match canvas.get_submission_by_user(letter.user_id).await {
+                    Ok(submission) => submission,
+                    Err(e) => {
+                        dead_letter(
+                            &canvas.config,
+                            letter.user_id,
+                            letter.attempt,
+                            format!(
+                                "Failed to fetch submission of user {}: {}",
+                                letter.user_id, e
+                            ),
+                            letter.zero_posted,
+                        );
+                        continue;
+                    }
+                };
+                // the same gates as the daemon, the grade may have changed since.
+                // Our own placeholder 0 leaves it graded but still due.
+                if !letter.zero_posted && !is_due(&canvas, &submission) {
+                    info!("User {} is no longer due for grading", letter.user_id);
+                    continue;
+                }
+                let submission = match without_manual_grades(&canvas, vec![submission]).await {
+                    Ok(mut pending) => match pending.pop() {
+                        Some(submission) => submission,
+                        None => continue,
+                    },
+                    Err(e) => {
+                        dead_letter(
+                            &canvas.config,
+                            letter.user_id,
+                            letter.attempt,
+                            e.to_string(),
+                            letter.zero_posted,
+                        );
+                        continue;
+                    }
+                };
+                if let Outcome::Finished =
+                    // the daemon already posted the progress comment of this attempt
+                    grade_submission(docker.clone(), canvas.clone(), submission, false).await
+                {
+                    finished += 1;
+                }
+            }
+            info!("Regraded {} of {} submissions", finished, letters.len());
+        }
+        Commands::List { config, since } => {
+            let config = load_config(&config)?;
+            let client = build_client(&config)?;
+            let canvas = Canvas::new(Arc::new(client), Arc::new(config));
+            let since = since
+                .map(|since| DateTime::parse_from_rfc3339(&since))
+                .transpose()
+                .map_err(|e| format!("Invalid --since time: {}", e))?;
+            let submissions = canvas
+                .get_all_sub(|sub| match since {
+                    Some(since) => sub.submitted_after(since),
+                    None => true,
+                })
+                .await?;
+            for sub in &submissions {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    sub.user_id,
+                    sub.workflow_state,
+                    sub.attempt.map(|a| a.to_string()).unwrap_or_default(),
+                    sub.submitted_at.as_deref().unwrap_or("-")
+                );
             }
+            info!("Listed {} submissions", submissions.len());
         }
-        #[allow(unused_variables)]
         Commands::Execute {
             config,
             pipeline,
             sub_id,
             url,
+            keep_workdir,
+            plan,
+            attach,
+            in_container,
+            output,
+            json,
         } => {
+            // optional in clap so that `--print-config` works without it
+            let Some(sub_id) = sub_id else {
+                let mut command = Cli::command();
+                command.build();
+                command
+                    .find_subcommand_mut("execute")
+                    .expect("execute is a subcommand")
+                    .error(
+                        clap::error::ErrorKind::MissingRequiredArgument,
+                        "the following required arguments were not provided:\n  --sub-id <SUB_ID>",
+                    )
+                    .exit();
+            };
             let config = load_config(&config)?;
-            let canvas = Arc::new(Canvas::new(Arc::new(client), Arc::new(config)));
+            let mut pipeline = match worker::parse_config(&pipeline) {
+                Ok(pipeline) => pipeline,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            // plan before touching Canvas, the submission may not even exist
+            if plan {
+                let workspace = Path::new(&config.workspace_dir).join(&sub_id);
+                let mut worker =
+                    worker::Worker::from_steps(pipeline.variables, pipeline.steps, workspace);
+                if let Some(url) = url {
+                    worker.modify_variable("url", Value::String(url));
+                }
+                println!("{}", worker.plan()?);
+                return Ok(());
+            }
 
-            let pipeline = worker::parse_config(&pipeline);
-            let mut worker = worker::Worker::new(pipeline.variables);
-            for (name, step) in pipeline.steps {
-                info!("Adding task: {}", name);
-                let task = worker::Task::new(name, step.commands, worker.variables.clone());
-                worker.add_task(task);
+            if let Some(bytes) = config.max_workspace_bytes {
+                builtin::set_disk_quota(bytes);
             }
+            let client = build_client(&config)?;
+            let canvas = Arc::new(Canvas::new(Arc::new(client), Arc::new(config)));
 
-            // modify the pipeline variables
-            worker.modify_variable("url", Value::String(url));
+            let url = match url {
+                Some(url) => url,
+                None => {
+                    let submission = canvas.get_submission_by_user(sub_id.parse()?).await?;
+                    let (url, _) = submission.source()?;
+                    info!("Fetched submission URL from Canvas: {}", url);
+                    if let Some(path) = canvas.archive_submission(&submission).await? {
+                        info!("Archived submission at {}", path.display());
+                    }
+                    url
+                }
+            };
 
-            // Run the pipeline
-            worker.run().await;
+            let workspace = Path::new(&canvas.config.workspace_dir).join(&sub_id);
+            std::fs::create_dir_all(&workspace)?;
+            let workspace = std::fs::canonicalize(workspace)?;
+            let mut cleanup = std::mem::take(&mut pipeline.cleanup);
+            cleanup.push(workspace.to_string_lossy().into_owned());
+            let rubric = std::mem::take(&mut pipeline.rubric);
+            // a sandboxed pipeline changes into the workspace
+            let output = output
+                .map(|path| std::path::absolute(path).map(|p| p.to_string_lossy().into_owned()))
+                .transpose()?;
+            let attach = attach.map(std::path::absolute).transpose()?;
+            let sandbox = if in_container {
+                Some(Sandbox::new(
+                    Docker::connect_with_local_defaults()?,
+                    &canvas.config,
+                    sub_id.clone(),
+                ))
+            } else {
+                None
+            };
+
+            let cleanup = worker::CleanupGuard {
+                dirs: cleanup,
+                keep: keep_workdir,
+            };
+
+            // Run the pipeline, telling the daemon that failures from here on
+            // are not the grading image's
+            eprintln!("{}", PIPELINE_STARTED);
+            let worker = worker::run_pipeline(
+                pipeline,
+                workspace,
+                vec![("url", Value::String(url))],
+                canvas.config.allowed_commands.clone(),
+                sandbox,
+            )
+            .await?;
 
             info!("Upadting score");
+            // a broken score is the pipeline's fault, so nothing gets graded
             let final_score = worker
-                .variables
-                .lock()
-                .expect("should finished task")
-                .get("score")
-                .expect("should have score")
-                .clone()
-                .expect("should define score")
-                .as_integer()
-                .expect("should be integer") as u32;
+                .score()
+                .map_err(|e| format!("{}, check the pipeline", e))?;
             info!("Final score: {}", final_score);
+            let rubric_scores = worker.rubric_scores(&rubric)?;
+            for (criterion, points) in &rubric_scores {
+                info!("Rubric criterion {}: {}", criterion, points);
+            }
 
-            let comment = worker
-                .results
-                .into_iter()
-                .map(|(name, res)| res)
-                .reduce(|res, msg| res + &msg)
-                .expect("should have a result comment");
-            println!("Comment:\n{}", comment);
+            let mut comment = worker.report();
+            if canvas.config.verbose_comment {
+                comment.push_str(&worker.output_report());
+            }
+            let closing = if worker.all_passed() {
+                &canvas.config.success_comment
+            } else {
+                &canvas.config.partial_comment
+            };
+            if let Some(closing) = closing {
+                comment.push('\n');
+                comment.push_str(closing);
+            }
+            // `--json` alone keeps stdout for the JSON document
+            if json && output.is_none() {
+                eprintln!("Comment:\n{}", comment);
+            } else {
+                println!("Comment:\n{}", comment);
+            }
+            if output.is_some() || json {
+                let export = if json {
+                    let steps: Vec<_> = worker
+                        .results
+                        .iter()
+                        .map(|(name, result)| {
+                            serde_json::json!({
+                                "name": name,
+                                "status": result.status,
+                                "message": result.message,
+                                "output": result.output,
+                                "duration_secs": result.duration.as_secs_f64(),
+                            })
+                        })
+                        .collect();
+                    let rubric: serde_json::Map<_, _> = rubric_scores
+                        .iter()
+                        .map(|(criterion, points)| (criterion.clone(), (*points).into()))
+                        .collect();
+                    serde_json::to_string_pretty(&serde_json::json!({
+                        "sub_id": sub_id,
+                        "score": final_score,
+                        "comment": comment,
+                        "steps": steps,
+                        "rubric": rubric,
+                    }))?
+                } else {
+                    comment.clone()
+                };
+                match &output {
+                    Some(path) => {
+                        std::fs::write(path, export)?;
+                        info!("Wrote the result to {}", path);
+                    }
+                    None => println!("{}", export),
+                }
+            }
+
+            // the attachment may live in a working directory removed below
+            if let Some(attach) = attach {
+                canvas.upload_comment_file(sub_id.parse()?, &attach).await?;
+                info!("Uploaded {} as a comment attachment", attach.display());
+            }
 
-            //canvas
-            //    .update_score(sub_id.parse().unwrap(), final_score, &comment)
-            //    .await?;
+            drop(cleanup);
             info!("Pipeline finished");
         }
     }
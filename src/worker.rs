@@ -1,67 +1,350 @@
-use crate::builtin::{create_builtin_registry, BuiltinRegistry};
+use crate::builtin::{
+    create_builtin_registry, release_disk_quota, spawned_programs, BuiltinRegistry,
+};
+use crate::container::Sandbox;
+use futures::future::join_all;
 use indexmap::IndexMap;
-use log::{error, info};
-use serde::Deserialize;
-use std::collections::HashMap;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use tokio::time::{timeout, Duration, Instant};
 use toml::Value;
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Pipeline {
+    /// Directories removed once the pipeline has finished
+    #[serde(default)]
+    pub cleanup: Vec<String>,
     pub variables: HashMap<String, Option<Value>>,
     pub steps: IndexMap<String, Step>,
+    /// Canvas rubric criterion ids mapped to the variables holding their points
+    #[serde(default)]
+    pub rubric: IndexMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "lowercase")]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
 pub enum Command {
     Builtin {
         action: String,
         args: Option<Vec<String>>,
         abort_on_failure: Option<bool>,
+        /// Store the builtin's result, e.g. a pass count, into this variable.
+        /// Extra results, like the counts of `run_gtest`, go into
+        /// `{score_variable}_{name}`.
+        score_variable: Option<String>,
     },
     Custom {
         action: String,
         args: Option<Vec<String>>,
         abort_on_failure: Option<bool>,
+        /// Store the exit code into this variable instead of failing on non-zero
+        score_variable: Option<String>,
     },
+    /// `+` adds an integer to a variable, `set` stores any value as is
     Variable {
         operation: String,
         name: String,
-        value: i32,
+        value: Value,
     },
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Step {
     pub commands: Vec<Command>,
+    /// Adjacent parallel steps are run concurrently as one group
+    #[serde(default)]
+    pub parallel: bool,
+    /// Steps that must finish successfully before this one runs
+    #[serde(default)]
+    pub needs: Vec<String>,
+    /// Fail the step when its commands take longer than this many seconds
+    pub timeout: Option<u64>,
+    /// Hint appended to the result when the step fails
+    pub failure_message: Option<String>,
 }
 
-pub fn parse_config(file_path: &str) -> Pipeline {
-    let config_content = fs::read_to_string(file_path).expect("Failed to read config file");
-    toml::from_str(&config_content).expect("Failed to parse TOML")
+pub fn parse_config(file_path: &str) -> Result<Pipeline, Box<dyn Error>> {
+    let config_content = fs::read_to_string(file_path)
+        .map_err(|e| format!("Failed to read pipeline file {}: {}", file_path, e))?;
+    let pipeline = toml::from_str(&config_content)
+        .map_err(|e| describe_toml_error(file_path, &config_content, &e))?;
+    check_rubric(&pipeline).map_err(|e| format!("Invalid pipeline file {}: {}", file_path, e))?;
+    Ok(pipeline)
+}
+
+/// Refuse rubric criteria mapped to variables that no step declares or sets,
+/// before anything gets graded
+fn check_rubric(pipeline: &Pipeline) -> Result<(), String> {
+    let assigned = assigned_variables(pipeline.steps.values().flat_map(|step| &step.commands));
+    for (criterion, variable) in &pipeline.rubric {
+        if !pipeline.variables.contains_key(variable) && !assigned.contains(variable) {
+            return Err(format!(
+                "rubric criterion {} reads variable {}, which is never declared or set",
+                criterion, variable
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Variables that `commands` set when they run, including the extra results
+/// of builtins stored next to their `score_variable`
+fn assigned_variables<'a>(commands: impl Iterator<Item = &'a Command>) -> HashSet<String> {
+    let builtin = create_builtin_registry();
+    let mut assigned = HashSet::new();
+    for command in commands {
+        match command {
+            Command::Builtin {
+                action,
+                score_variable: Some(variable),
+                ..
+            } => {
+                assigned.insert(variable.clone());
+                for name in builtin.extras(action) {
+                    assigned.insert(format!("{}_{}", variable, name));
+                }
+            }
+            Command::Custom {
+                score_variable: Some(variable),
+                ..
+            } => {
+                assigned.insert(variable.clone());
+            }
+            Command::Variable { name, .. } => {
+                assigned.insert(name.clone());
+            }
+            _ => {}
+        }
+    }
+    assigned
+}
+
+/// Render a TOML error as `file:line:column` with the offending source line
+fn describe_toml_error(file_path: &str, content: &str, err: &toml::de::Error) -> String {
+    let Some(span) = err.span() else {
+        return format!(
+            "Failed to parse pipeline file {}: {}",
+            file_path,
+            err.message()
+        );
+    };
+    let before = &content[..span.start];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    let source_line = content[line_start..].lines().next().unwrap_or_default();
+    // single-line spans point at the problematic key or value itself
+    let snippet = &content[span];
+    let (near, marker_width) = if snippet.contains('\n') {
+        (String::new(), 1)
+    } else {
+        (
+            format!("\n  near `{}`", snippet),
+            snippet.chars().count().max(1),
+        )
+    };
+
+    format!(
+        "Failed to parse pipeline file {}:{}:{}: {}{}\n{:>5} | {}\n      | {}{}",
+        file_path,
+        line,
+        column,
+        err.message().trim_end(),
+        near,
+        line,
+        source_line,
+        " ".repeat(column - 1),
+        "^".repeat(marker_width)
+    )
+}
+
+/// Run `pipeline` in `workspace` with `overrides` applied to its variables,
+/// returning the finished worker with its results and variables. Commands
+/// are limited to `allowed_commands` when given, and custom ones run in
+/// `sandbox` when given. The sandbox runs them in `workspace`, so the process
+/// changes into it too for builtins to resolve relative paths the same way.
+pub async fn run_pipeline(
+    pipeline: Pipeline,
+    workspace: PathBuf,
+    overrides: Vec<(&str, Value)>,
+    allowed_commands: Option<Vec<String>>,
+    sandbox: Option<Sandbox>,
+) -> Result<Worker, Box<dyn Error>> {
+    let mut worker = Worker::from_steps(pipeline.variables, pipeline.steps, workspace);
+    worker.allowed_commands = allowed_commands;
+    if sandbox.is_some() {
+        std::env::set_current_dir(&worker.workspace).map_err(|e| {
+            format!(
+                "Failed to change into {}: {}",
+                worker.workspace.display(),
+                e
+            )
+        })?;
+    }
+    worker.sandbox = sandbox;
+    for (name, value) in overrides {
+        worker.modify_variable(name, value);
+    }
+    worker.run().await?;
+    Ok(worker)
+}
+
+/// Remove the per-submission working directories left behind by a run
+pub fn cleanup_dirs(dirs: &[String]) {
+    for dir in dirs {
+        let path = Path::new(dir);
+        if !path.exists() {
+            continue;
+        }
+        release_disk_quota(path);
+        match fs::remove_dir_all(path) {
+            Ok(()) => info!("Removed working directory: {}", dir),
+            Err(e) => error!("Failed to remove working directory {}: {}", dir, e),
+        }
+    }
+}
+
+/// Removes working directories once dropped, so that a pipeline returning
+/// early with an error does not leave them behind
+pub struct CleanupGuard {
+    pub dirs: Vec<String>,
+    /// Keep the directories for debugging instead
+    pub keep: bool,
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        if self.keep {
+            info!("Keeping working directories: {:?}", self.dirs);
+        } else {
+            cleanup_dirs(&self.dirs);
+        }
+    }
+}
+
+/// Captured output of a command, labelling the streams when both are present
+fn command_output(output: &std::process::Output) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match (stdout.trim().is_empty(), stderr.trim().is_empty()) {
+        (_, true) => stdout.into_owned(),
+        (true, false) => stderr.into_owned(),
+        (false, false) => format!("stdout:\n{}\nstderr:\n{}", stdout, stderr),
+    }
+}
+
+const LABEL_WIDTH: usize = 10;
+const STATUS_WIDTH: usize = 20;
+/// Bytes of a step's output quoted by `Worker::output_report`
+const MAX_STEP_OUTPUT: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepStatus {
+    Ok,
+    Failed,
+    Skipped,
+    /// Failed with `abort_on_failure`, stopping the pipeline
+    Aborted,
+}
+
+/// Outcome of one step, rendered into the comment table by `render`
+#[derive(Debug, Clone)]
+pub struct StepResult {
+    pub status: StepStatus,
+    /// Output explaining a failure, empty when there is nothing to report
+    pub message: String,
+    /// Combined output of the step's custom commands
+    pub output: String,
+    pub duration: Duration,
+}
+
+impl StepResult {
+    fn new(status: StepStatus, message: impl Into<String>) -> StepResult {
+        StepResult {
+            status,
+            message: message.into(),
+            output: String::new(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    fn with_output(mut self, output: String) -> StepResult {
+        self.output = output;
+        self
+    }
+
+    /// Table row of the step `name` followed by its message
+    pub fn render(&self, name: &str) -> String {
+        let status = match self.status {
+            StepStatus::Ok => "Ok",
+            StepStatus::Failed | StepStatus::Aborted => "Failed",
+            StepStatus::Skipped => "Skipped",
+        };
+        let mut row = format!(
+            "{:<width$} {:>width2$}\n",
+            format!("[{}]", name),
+            status,
+            width = LABEL_WIDTH,
+            width2 = STATUS_WIDTH
+        );
+        if !self.message.is_empty() {
+            row.push_str(&self.message);
+            if !self.message.ends_with('\n') {
+                row.push('\n');
+            }
+        }
+        if self.status == StepStatus::Aborted {
+            row.push_str("Test aborted.\n");
+        }
+        row
+    }
 }
 
 pub struct Task {
     name: String,
     commands: Vec<Command>,
+    parallel: bool,
+    needs: Vec<String>,
+    timeout: Option<u64>,
+    failure_message: Option<String>,
     variables: Arc<Mutex<HashMap<String, Option<Value>>>>,
 }
 
 pub struct Worker {
     tasks: Vec<Task>,
-    pub results: IndexMap<String, String>,
+    workspace: PathBuf,
+    pub results: IndexMap<String, StepResult>,
     pub variables: Arc<Mutex<HashMap<String, Option<Value>>>>,
+    /// Executables custom commands and builtins may run, any when `None`
+    pub allowed_commands: Option<Vec<String>>,
+    /// Run custom commands in containers instead of on the host. Builtins
+    /// that run programs are refused then, they would run on the host.
+    pub sandbox: Option<Sandbox>,
 }
 
 impl Worker {
-    pub fn new(vars: HashMap<String, Option<Value>>) -> Worker {
+    /// `workspace` is exported to custom commands as `SEP_ROOT_DIR` and
+    /// available to builtins as the `workspace` variable
+    pub fn new(mut vars: HashMap<String, Option<Value>>, workspace: PathBuf) -> Worker {
+        vars.entry("workspace".to_string())
+            .or_insert(Some(Value::String(
+                workspace.to_string_lossy().into_owned(),
+            )));
         Worker {
             tasks: vec![],
+            workspace,
             results: IndexMap::new(),
             variables: Arc::new(Mutex::new(vars)),
+            allowed_commands: None,
+            sandbox: None,
         }
     }
 
@@ -75,47 +358,448 @@ impl Worker {
             });
     }
 
+    /// Worker with one task per pipeline step
+    pub fn from_steps(
+        vars: HashMap<String, Option<Value>>,
+        steps: IndexMap<String, Step>,
+        workspace: PathBuf,
+    ) -> Worker {
+        let mut worker = Worker::new(vars, workspace);
+        for (name, step) in steps {
+            info!("Adding task: {}", name);
+            let task = Task::new(name, step, worker.variables.clone());
+            worker.add_task(task);
+        }
+        worker
+    }
+
+    /// Final score, read from the `score` variable
+    pub fn score(&self) -> Result<u32, Box<dyn Error>> {
+        let variables = self.variables.lock().expect("Failed to lock variables");
+        match variables.get("score") {
+            Some(Some(Value::Integer(score))) => u32::try_from(*score).map_err(|_| {
+                format!("Variable score is {}, which is not a valid score", score).into()
+            }),
+            Some(Some(value)) => {
+                Err(format!("Variable score must be an integer, found {}", value).into())
+            }
+            Some(None) => Err("Variable score is declared but never set".into()),
+            None => Err("Pipeline declares no score variable".into()),
+        }
+    }
+
+    /// Points of each rubric criterion, read from the variables `rubric` maps
+    /// them to
+    pub fn rubric_scores(
+        &self,
+        rubric: &IndexMap<String, String>,
+    ) -> Result<Vec<(String, f64)>, Box<dyn Error>> {
+        let variables = self.variables.lock().expect("Failed to lock variables");
+        rubric
+            .iter()
+            .map(|(criterion, variable)| {
+                let points = match variables.get(variable) {
+                    Some(Some(Value::Integer(points))) => *points as f64,
+                    Some(Some(Value::Float(points))) => *points,
+                    _ => {
+                        return Err(format!(
+                            "Rubric criterion {} needs a numeric variable {}",
+                            criterion, variable
+                        )
+                        .into())
+                    }
+                };
+                Ok((criterion.clone(), points))
+            })
+            .collect()
+    }
+
     pub fn add_task(&mut self, task: Task) {
         self.tasks.push(task);
     }
 
-    pub async fn run(&mut self) {
+    /// Order tasks so that every task comes after the tasks it needs
+    fn schedule(&mut self) -> Result<(), Box<dyn Error>> {
+        let names: HashSet<&str> = self.tasks.iter().map(|task| task.name.as_str()).collect();
+        for task in &self.tasks {
+            if let Some(need) = task.needs.iter().find(|n| !names.contains(n.as_str())) {
+                return Err(format!("Step '{}' needs unknown step '{}'", task.name, need).into());
+            }
+        }
+
+        let mut pending = std::mem::take(&mut self.tasks);
+        let mut done: HashSet<String> = HashSet::new();
+        while !pending.is_empty() {
+            match pending
+                .iter()
+                .position(|task| task.needs.iter().all(|n| done.contains(n)))
+            {
+                Some(index) => {
+                    let task = pending.remove(index);
+                    done.insert(task.name.clone());
+                    self.tasks.push(task);
+                }
+                None => {
+                    let cycle: Vec<&str> = pending.iter().map(|task| task.name.as_str()).collect();
+                    return Err(
+                        format!("Dependency cycle between steps: {}", cycle.join(", ")).into(),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolved steps in execution order, without running anything, followed
+    /// by the variables that no step declares or sets
+    pub fn plan(&mut self) -> Result<String, Box<dyn Error>> {
+        self.schedule()?;
+        // variables the steps set themselves only get their values at runtime
+        let assigned = assigned_variables(self.tasks.iter().flat_map(|task| &task.commands));
+        let mut plan = String::new();
+        let mut unresolved: Vec<String> = Vec::new();
+        for task in &self.tasks {
+            let (task_plan, task_unresolved) = task.plan(&assigned);
+            plan.push_str(&task_plan);
+            for name in task_unresolved {
+                if !unresolved.contains(&name) {
+                    unresolved.push(name);
+                }
+            }
+        }
+        if !unresolved.is_empty() {
+            plan.push_str(&format!(
+                "Unresolved variables: {}\n",
+                unresolved.join(", ")
+            ));
+        }
+        Ok(plan)
+    }
+
+    /// Refuse commands whose executable is not in `allowed_commands`.
+    /// Builtins running a program named by a variable are checked once it
+    /// is resolved.
+    fn check_commands(&self) -> Result<(), Box<dyn Error>> {
+        let Some(allowed) = &self.allowed_commands else {
+            return Ok(());
+        };
+        for task in &self.tasks {
+            for command in &task.commands {
+                let programs = match command {
+                    Command::Custom { action, .. } => vec![action.clone()],
+                    Command::Builtin { action, args, .. } => {
+                        spawned_programs(action, args.as_deref().unwrap_or_default())
+                    }
+                    Command::Variable { .. } => vec![],
+                };
+                if let Some(program) = programs
+                    .iter()
+                    .find(|program| !program.starts_with("var::") && !allowed.contains(program))
+                {
+                    return Err(format!(
+                        "Step '{}' runs '{}', which is not in allowed_commands",
+                        task.name, program
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse builtins that run programs while custom commands are
+    /// sandboxed, since builtins run on the host and would run student code
+    /// outside the container
+    fn check_sandbox(&self) -> Result<(), Box<dyn Error>> {
+        if self.sandbox.is_none() {
+            return Ok(());
+        }
+        for task in &self.tasks {
+            for command in &task.commands {
+                let Command::Builtin { action, args, .. } = command else {
+                    continue;
+                };
+                if !spawned_programs(action, args.as_deref().unwrap_or_default()).is_empty() {
+                    return Err(format!(
+                        "Step '{}' uses builtin {}, which runs programs on the host and cannot be sandboxed, use a custom command instead",
+                        task.name, action
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Table of every step result, as posted in the comment
+    pub fn report(&self) -> String {
+        self.results
+            .iter()
+            .map(|(name, result)| result.render(name))
+            .collect()
+    }
+
+    /// Output of every step that printed any, fenced as code blocks and cut
+    /// to `MAX_STEP_OUTPUT` bytes each
+    pub fn output_report(&self) -> String {
+        let mut report = String::new();
+        for (name, result) in &self.results {
+            let output = result.output.trim_end();
+            if output.is_empty() {
+                continue;
+            }
+            let mut end = output.len().min(MAX_STEP_OUTPUT);
+            while !output.is_char_boundary(end) {
+                end -= 1;
+            }
+            let truncated = if end < output.len() {
+                "\n…(truncated)"
+            } else {
+                ""
+            };
+            report.push_str(&format!(
+                "\nOutput of [{}]:\n```\n{}{}\n```\n",
+                name,
+                &output[..end],
+                truncated
+            ));
+        }
+        report
+    }
+
+    /// Whether every step ran and passed
+    pub fn all_passed(&self) -> bool {
+        self.results.len() == self.tasks.len()
+            && self
+                .results
+                .values()
+                .all(|result| result.status == StepStatus::Ok)
+    }
+
+    pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        self.schedule()?;
+        self.check_commands()?;
+        self.check_sandbox()?;
         let builtin = create_builtin_registry();
-        for task in &mut self.tasks {
-            match task.run(&builtin).await {
-                Ok(msg) => {
-                    info!("Task executed successfully: {}", msg);
-                    self.results.insert(task.name.clone(), msg);
+        let mut failed: HashSet<String> = HashSet::new();
+        let mut rest = &mut self.tasks[..];
+        while let Some(first) = rest.first() {
+            // a run of adjacent parallel tasks forms one concurrent group,
+            // as long as none of them needs another task of the same group
+            let len = if first.parallel {
+                let mut group_names = HashSet::new();
+                rest.iter()
+                    .take_while(|task| {
+                        let independent = task.parallel
+                            && !task.needs.iter().any(|n| group_names.contains(n.as_str()));
+                        group_names.insert(task.name.as_str());
+                        independent
+                    })
+                    .count()
+            } else {
+                1
+            };
+            let (group, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            rest = tail;
+
+            // dependents of a failed task are skipped instead of run
+            let skip: Vec<bool> = group
+                .iter()
+                .map(|task| task.needs.iter().any(|n| failed.contains(n)))
+                .collect();
+            let outcomes = join_all(group.iter_mut().zip(&skip).filter(|(_, skip)| !**skip).map(
+                |(task, _)| {
+                    task.run_with_timeout(
+                        &builtin,
+                        &self.workspace,
+                        self.sandbox.as_ref(),
+                        self.allowed_commands.as_deref(),
+                    )
+                },
+            ))
+            .await;
+
+            let mut outcomes = outcomes.into_iter();
+            let mut aborted = false;
+            for (task, skip) in group.iter().zip(skip) {
+                if skip {
+                    info!("Skipping task {} due to a failed dependency", task.name);
+                    failed.insert(task.name.clone());
+                    self.results
+                        .insert(task.name.clone(), StepResult::new(StepStatus::Skipped, ""));
+                    continue;
                 }
-                Err(err) => {
-                    error!("Error running task: {}", err);
-                    self.results.insert(task.name.clone(), err.to_string());
-                    break;
+                let result = outcomes
+                    .next()
+                    .expect("should have an outcome per task run");
+                match result.status {
+                    StepStatus::Ok => {
+                        info!("Task {} finished in {:?}", task.name, result.duration)
+                    }
+                    StepStatus::Failed => {
+                        error!("Task {} failed: {}", task.name, result.message);
+                        failed.insert(task.name.clone());
+                    }
+                    StepStatus::Skipped => {}
+                    StepStatus::Aborted => {
+                        error!("Task {} aborted: {}", task.name, result.message);
+                        aborted = true;
+                    }
                 }
+                self.results.insert(task.name.clone(), result);
+            }
+            if aborted {
+                break;
             }
         }
+        Ok(())
     }
 }
 
 impl Task {
     pub fn new(
         name: String,
-        commands: Vec<Command>,
+        step: Step,
         variables: Arc<Mutex<HashMap<String, Option<Value>>>>,
     ) -> Task {
         Task {
             name,
-            commands,
+            commands: step.commands,
+            parallel: step.parallel,
+            needs: step.needs,
+            timeout: step.timeout,
+            failure_message: step.failure_message,
             variables,
         }
     }
 
-    pub async fn run(&mut self, builtin: &BuiltinRegistry) -> Result<String, Box<dyn Error>> {
+    /// Substitute `var::` arguments with the current variable values
+    fn resolve_args(&self, args: &Option<Vec<String>>) -> Result<Vec<String>, Box<dyn Error>> {
+        let variables = self.variables.lock().expect("Failed to lock variables");
+        args.iter()
+            .flatten()
+            .map(|arg| {
+                let Some(name) = arg.strip_prefix("var::") else {
+                    return Ok(arg.clone());
+                };
+                match variables.get(name) {
+                    Some(Some(value)) => Ok(value.to_string().trim_matches('\"').to_string()),
+                    Some(None) => Err(format!("Variable {} is used before it is set", name).into()),
+                    None => Err(format!("Variable {} is not declared", name).into()),
+                }
+            })
+            .collect()
+    }
+
+    /// Describe the commands of this task with the arguments known so far
+    /// resolved. Variables that are neither declared nor in `assigned` are
+    /// returned as unresolved.
+    fn plan(&self, assigned: &HashSet<String>) -> (String, Vec<String>) {
+        let mut plan = format!("[{}]", self.name);
+        if !self.needs.is_empty() {
+            plan.push_str(&format!(" needs: {}", self.needs.join(", ")));
+        }
+        if self.parallel {
+            plan.push_str(" (parallel)");
+        }
+        if let Some(secs) = self.timeout {
+            plan.push_str(&format!(" (timeout {}s)", secs));
+        }
+        plan.push('\n');
+
+        let variables = self.variables.lock().expect("Failed to lock variables");
+        let mut unresolved = Vec::new();
+        let mut resolve = |args: &Option<Vec<String>>| -> Vec<String> {
+            args.iter()
+                .flatten()
+                .map(|arg| {
+                    let Some(name) = arg.strip_prefix("var::") else {
+                        return arg.clone();
+                    };
+                    match variables.get(name) {
+                        Some(Some(value)) => value.to_string().trim_matches('\"').to_string(),
+                        // declared or set by a step, resolved when it runs
+                        Some(None) => arg.clone(),
+                        None if assigned.contains(name) => arg.clone(),
+                        None => {
+                            unresolved.push(name.to_string());
+                            arg.clone()
+                        }
+                    }
+                })
+                .collect()
+        };
+        for command in &self.commands {
+            let line = match command {
+                Command::Builtin { action, args, .. } => {
+                    format!("builtin {} {:?}", action, resolve(args))
+                }
+                Command::Custom { action, args, .. } => {
+                    format!("custom {} {:?}", action, resolve(args))
+                }
+                Command::Variable {
+                    operation,
+                    name,
+                    value,
+                } => format!("variable {} {} {}", name, operation, value),
+            };
+            plan.push_str(&format!("  {}\n", line));
+        }
+        (plan, unresolved)
+    }
+
+    /// Run the task, failing it once the step timeout elapses
+    pub async fn run_with_timeout(
+        &mut self,
+        builtin: &BuiltinRegistry,
+        workspace: &Path,
+        sandbox: Option<&Sandbox>,
+        allowed_commands: Option<&[String]>,
+    ) -> StepResult {
+        let start = Instant::now();
+        let mut result = match self.timeout {
+            None => {
+                self.run(builtin, workspace, sandbox, allowed_commands)
+                    .await
+            }
+            Some(secs) => {
+                let run = self.run(builtin, workspace, sandbox, allowed_commands);
+                match timeout(Duration::from_secs(secs), run).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        error!("Task {} timed out after {} seconds", self.name, secs);
+                        StepResult::new(
+                            StepStatus::Failed,
+                            format!("Timed out after {} seconds", secs),
+                        )
+                    }
+                }
+            }
+        };
+        result.duration = start.elapsed();
+        // point the student at the step's `failure_message`
+        if let (StepStatus::Failed | StepStatus::Aborted, Some(hint)) =
+            (result.status, &self.failure_message)
+        {
+            if !result.message.is_empty() && !result.message.ends_with('\n') {
+                result.message.push('\n');
+            }
+            result.message.push_str(hint);
+        }
+        result
+    }
+
+    /// Run the commands of the task, custom ones in `sandbox` if given.
+    /// Builtins fail when they would run a program not in `allowed_commands`.
+    pub async fn run(
+        &mut self,
+        builtin: &BuiltinRegistry,
+        workspace: &Path,
+        sandbox: Option<&Sandbox>,
+        allowed_commands: Option<&[String]>,
+    ) -> StepResult {
         info!("Running task: {}", self.name);
-        // 定义每列的宽度
-        let label_width = 10;
-        let status_width = 20;
-        let label = format!("[{}]", self.name);
+        let mut captured = String::new();
 
         for command in &self.commands {
             match command {
@@ -123,118 +807,104 @@ impl Task {
                     action,
                     args,
                     abort_on_failure,
+                    score_variable,
                 } => {
-                    let mut args = args.clone().unwrap_or_default();
-                    args.iter_mut()
-                        .filter(|arg| arg.starts_with("var::"))
-                        .for_each(|arg| {
-                            *arg = self
-                                .variables
-                                .lock()
-                                .expect("should be able to lock the varibales")
-                                .get(&arg.replace("var::", ""))
-                                .expect("should have varibales")
-                                .as_ref()
-                                .unwrap()
-                                .to_string()
-                                .trim_matches('\"')
-                                .to_string();
-                        });
+                    let args = match self.resolve_args(args) {
+                        Ok(args) => args,
+                        Err(e) => {
+                            return StepResult::new(failure_status(abort_on_failure), e.to_string())
+                                .with_output(captured)
+                        }
+                    };
+                    if let Some(allowed) = allowed_commands {
+                        let programs = spawned_programs(action, &args);
+                        if let Some(program) = programs.iter().find(|p| !allowed.contains(p)) {
+                            return StepResult::new(
+                                failure_status(abort_on_failure),
+                                format!(
+                                    "{} runs {}, which is not in allowed_commands",
+                                    action, program
+                                ),
+                            )
+                            .with_output(captured);
+                        }
+                    }
                     info!("Running builtin command: {} with ({:?})", action, args);
 
-                    if let Err(e) = builtin.execute(action, args).await {
-                        error!("Error executing builtin command '{}': {}", action, e);
-                        if abort_on_failure.unwrap_or(false) {
-                            error!("Aborting task due to failure");
-                            return Err(format!(
-                                "{:<width$} {:>width2$}\n{}\nTest aborted.\n",
-                                label,
-                                "Failed",
-                                e,
-                                width = label_width,
-                                width2 = status_width
-                            )
-                            .into());
-                        } else {
-                            return Ok(format!(
-                                "{:<width$} {:>width2$}\n{}",
-                                label,
-                                "Failed",
-                                e,
-                                width = label_width,
-                                width2 = status_width
-                            ));
+                    let result = builtin.execute(action, args).await;
+                    if let (Some(variable), Ok(output)) = (score_variable, &result) {
+                        let mut variables =
+                            self.variables.lock().expect("Failed to lock variables");
+                        if let Some(value) = output.value {
+                            info!("Storing result {} into {}", value, variable);
+                            variables.insert(variable.clone(), Some(Value::Integer(value)));
+                        }
+                        for (name, value) in &output.extra {
+                            let name = format!("{}_{}", variable, name);
+                            info!("Storing {} into {}", value, name);
+                            variables.insert(name, Some(Value::Integer(*value)));
                         }
                     }
+                    if let Err(e) = result {
+                        error!("Error executing builtin command '{}': {}", action, e);
+                        return StepResult::new(failure_status(abort_on_failure), e.to_string())
+                            .with_output(captured);
+                    }
                 }
                 Command::Custom {
                     action,
                     args,
                     abort_on_failure,
+                    score_variable,
                 } => {
-                    let mut args = args.clone().unwrap_or_default();
-                    args.iter_mut()
-                        .filter(|arg| arg.starts_with("var::"))
-                        .for_each(|arg| {
-                            *arg = self
-                                .variables
-                                .lock()
-                                .expect("should be able to lock the varibales")
-                                .get(&arg.replace("var::", ""))
-                                .expect("should have varibales")
-                                .as_ref()
-                                .unwrap()
-                                .to_string()
-                                .trim_matches('\"')
-                                .to_string();
-                        });
+                    let args = match self.resolve_args(args) {
+                        Ok(args) => args,
+                        Err(e) => {
+                            return StepResult::new(failure_status(abort_on_failure), e.to_string())
+                                .with_output(captured)
+                        }
+                    };
                     info!("Running custom command: {} with ({:?})", action, args);
 
-                    let root_dir = env!("CARGO_MANIFEST_DIR");
-                    let cmd = std::process::Command::new(action.clone())
-                        .args(&args[..])
-                        .env("SEP_ROOT_DIR", root_dir)
-                        .output();
+                    let cmd: Result<_, Box<dyn Error>> = match sandbox {
+                        Some(sandbox) => sandbox.output(action, &args, workspace).await,
+                        None => tokio::process::Command::new(action.clone())
+                            .args(&args[..])
+                            .env("SEP_ROOT_DIR", workspace)
+                            .kill_on_drop(true)
+                            .output()
+                            .await
+                            .map_err(Into::into),
+                    };
 
                     match cmd {
                         Ok(output) => {
-                            let stdout = std::str::from_utf8(&output.stdout).unwrap();
-                            //println!("{}", stdout);
+                            let report = command_output(&output);
+                            captured.push_str(&report);
+
+                            if let (Some(variable), Some(code)) =
+                                (score_variable, output.status.code())
+                            {
+                                info!("Storing exit code {} into {}", code, variable);
+                                self.variables
+                                    .lock()
+                                    .expect("Failed to lock variables")
+                                    .insert(variable.clone(), Some(Value::Integer(code.into())));
+                                continue;
+                            }
 
                             if !output.status.success() {
-                                if abort_on_failure.unwrap_or(false) {
-                                    error!("Aborting task due to failure");
-                                    return Err(format!(
-                                        "{:<width$} {:>width2$}\n{}\nTest aborted.\n",
-                                        label,
-                                        "Failed",
-                                        stdout,
-                                        width = label_width,
-                                        width2 = status_width
-                                    )
-                                    .into());
-                                } else {
-                                    return Ok(format!(
-                                        "{:<width$} {:>width2$}\n{}",
-                                        label,
-                                        "Failed",
-                                        stdout,
-                                        width = label_width,
-                                        width2 = status_width
-                                    ));
-                                }
+                                return StepResult::new(failure_status(abort_on_failure), report)
+                                    .with_output(captured);
                             }
                         }
                         Err(e) => {
                             error!("Error executing custom command '{}': {}", action, e);
-                            if abort_on_failure.unwrap_or(false) {
-                                error!("Aborting task due to failure");
-                                return Err(
-                                    format!("{} aborted due to failure: {}\n", label, e).into()
-                                );
-                            } else {
-                                return Ok(format!("{} execution failed due to: {}\n", label, e));
-                            }
+                            return StepResult::new(
+                                failure_status(abort_on_failure),
+                                format!("Failed to run {}: {}", action, e),
+                            )
+                            .with_output(captured);
                         }
                     }
                 }
@@ -244,15 +914,22 @@ impl Task {
                     value,
                 } => {
                     info!("Running variable command: {} {} {}", name, operation, value);
-                    if operation == "+" {
-                        let mut variables =
-                            self.variables.lock().expect("Failed to lock variables");
-                        if let Some(current_value) = variables.get_mut(name) {
+                    let mut variables = self.variables.lock().expect("Failed to lock variables");
+                    match operation.as_str() {
+                        "+" => {
+                            let Value::Integer(value) = value else {
+                                error!("Cannot add non-integer value {} to {}", value, name);
+                                continue;
+                            };
+                            let current_value =
+                                variables.entry(name.clone()).or_insert_with(|| {
+                                    warn!("Variable {} is not declared, starting from 0", name);
+                                    Some(Value::Integer(0))
+                                });
                             match current_value {
                                 Some(Value::Integer(num)) => {
                                     let current_int = *num;
-                                    *current_value =
-                                        Some(Value::Integer(current_int + *value as i64));
+                                    *current_value = Some(Value::Integer(current_int + value));
                                 }
                                 _ => {
                                     error!(
@@ -262,16 +939,105 @@ impl Task {
                                 }
                             }
                         }
+                        "set" => {
+                            variables.insert(name.clone(), Some(value.clone()));
+                        }
+                        _ => {
+                            error!("Unknown variable operation: {}", operation);
+                        }
                     }
                 }
             }
         }
-        Ok(format!(
-            "{:<width$} {:>width2$}\n",
-            label,
-            "Ok",
-            width = label_width,
-            width2 = status_width
-        ))
+        StepResult::new(StepStatus::Ok, "").with_output(captured)
+    }
+}
+
+fn failure_status(abort_on_failure: &Option<bool>) -> StepStatus {
+    if abort_on_failure.unwrap_or(false) {
+        error!("Aborting task due to failure");
+        StepStatus::Aborted
+    } else {
+        StepStatus::Failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(steps: &str) -> Worker {
+        let pipeline: Pipeline = toml::from_str(&format!("[variables]\n{}", steps)).unwrap();
+        Worker::from_steps(pipeline.variables, pipeline.steps, PathBuf::from("/tmp"))
+    }
+
+    fn order(worker: &Worker) -> Vec<&str> {
+        worker.tasks.iter().map(|task| task.name.as_str()).collect()
+    }
+
+    #[test]
+    fn schedule_runs_needed_steps_first() {
+        let mut worker = worker(
+            r#"
+            [steps.score]
+            needs = ["tests", "style"]
+            commands = []
+            [steps.tests]
+            needs = ["compile"]
+            commands = []
+            [steps.compile]
+            commands = []
+            [steps.style]
+            commands = []
+            "#,
+        );
+        worker.schedule().unwrap();
+        assert_eq!(order(&worker), ["compile", "tests", "style", "score"]);
+    }
+
+    #[test]
+    fn schedule_rejects_cycles() {
+        let mut worker = worker(
+            r#"
+            [steps.a]
+            needs = ["c"]
+            commands = []
+            [steps.b]
+            needs = ["a"]
+            commands = []
+            [steps.c]
+            needs = ["b"]
+            commands = []
+            [steps.d]
+            commands = []
+            "#,
+        );
+        let error = worker.schedule().unwrap_err().to_string();
+        assert_eq!(error, "Dependency cycle between steps: a, b, c");
+    }
+
+    #[test]
+    fn schedule_rejects_steps_needing_themselves() {
+        let mut worker = worker(
+            r#"
+            [steps.a]
+            needs = ["a"]
+            commands = []
+            "#,
+        );
+        assert!(worker.schedule().is_err());
+    }
+
+    #[test]
+    fn schedule_rejects_unknown_steps() {
+        let mut worker = worker(
+            r#"
+            [steps.a]
+            needs = ["missing"]
+            commands = []
+            "#,
+        );
+        let error = worker.schedule().unwrap_err().to_string();
+        assert_eq!(error, "Step 'a' needs unknown step 'missing'");
     }
 }